@@ -0,0 +1,808 @@
+//! Provide binary bytecode format to save and load compiled BF programs.
+//!
+//! The file starts with the header: magic number `BTFC`, format version as little-endian `u16`,
+//! number of bytes of the cell, `1` if the cell is signed or `0` otherwise, optimization level,
+//! flags, where bit `0` means the file has the source-span table, and the number of BF instructions
+//! of the source program. The header is followed by the name of the source file and the list of
+//! operations with indices of BF instructions they are built from. The source-span table of line,
+//! column, byte offset and byte length of every BF instruction is written at the end. Numbers are
+//! written as LEB128 variable-length integers, signed ones are zigzag encoded.
+use crate::ir::{Block, Instruction, Op, OptimizationLevel, Program, SourceLocation};
+use crate::CellKind;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Magic number bytecode files start with.
+pub const MAGIC: [u8; 4] = *b"BTFC";
+/// Version of bytecode format written by this library.
+pub const VERSION: u16 = 1;
+
+/// Flag of the header set when the file has the source-span table.
+const SPANS_FLAG: u8 = 1;
+/// The number of BF instructions of the shortest loop replaced by `Clear`, `Scan` or `MultiplyAdd`.
+const IDIOM_SPAN: usize = 3;
+/// The maximum number of BF instructions of the source program of the file without the source-span
+/// table. Nothing else in such file proves the size of the program, while the profiler counts
+/// executions of every BF instruction.
+pub const MAX_SOURCE_LEN_WITHOUT_SPANS: usize = 1 << 24;
+
+/// Provide structure to represent the cell type the program is compiled for.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CellWidth {
+    /// Number of bytes occupied by the value of the cell.
+    pub bytes: u8,
+    /// Whether the value of the cell can be negative.
+    pub signed: bool,
+}
+
+impl CellWidth {
+    /// Get the width of the cell type.
+    pub fn of<T: CellKind>() -> CellWidth {
+        CellWidth {
+            bytes: T::BYTES as u8,
+            signed: T::SIGNED,
+        }
+    }
+}
+
+/// Provide human-readable format of the cell type, like `u8` or `i32`.
+impl fmt::Display for CellWidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.signed { 'i' } else { 'u' };
+        write!(f, "{}{}", sign, u32::from(self.bytes) * 8)
+    }
+}
+
+/// Provide structure to represent the header of bytecode file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Header {
+    /// Version of bytecode format.
+    pub version: u16,
+    /// The cell type the program is compiled for.
+    pub cell: CellWidth,
+    /// Optimizations applied to the program.
+    pub level: OptimizationLevel,
+    /// Whether the file has the source-span table.
+    pub spans: bool,
+    /// The number of BF instructions of the source program.
+    pub source_len: usize,
+}
+
+/// Provide enum of errors for loading bytecode files.
+#[derive(Debug)]
+pub enum BytecodeError {
+    /// IO error while reading the file.
+    IOError(io::Error),
+    /// Represent the case when the file doesn't start with the magic number.
+    InvalidMagic,
+    /// Represent the case when the file is written in another version of the format.
+    UnsupportedVersion(u16),
+    /// Represent the case when the cell type of the header is unknown.
+    InvalidCellWidth(u8),
+    /// Represent the case when the optimization level of the header is unknown.
+    InvalidOptimizationLevel(u8),
+    /// Represent the case when the file ends in the middle of the program.
+    Truncated,
+    /// Represent the case when the program in the file is malformed.
+    Corrupted(String),
+    /// Represent the case when the operation is built from no BF instructions, from fewer ones than
+    /// the loop it replaces has, or from ones out of the program.
+    InvalidSourceSpan(Range<usize>),
+    /// Represent the case when the file without the source-span table has more BF instructions
+    /// than [`MAX_SOURCE_LEN_WITHOUT_SPANS`].
+    TooLargeSource(usize),
+}
+
+/// Provide human-readable format of the bytecode errors.
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::IOError(source) => write!(f, "IO error: {}", source),
+            BytecodeError::InvalidMagic => write!(f, "not a bytecode file"),
+            BytecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported bytecode version {}, expected {}",
+                version, VERSION
+            ),
+            BytecodeError::InvalidCellWidth(bytes) => {
+                write!(f, "invalid cell width of {} bytes", bytes)
+            }
+            BytecodeError::InvalidOptimizationLevel(level) => {
+                write!(f, "invalid optimization level {}", level)
+            }
+            BytecodeError::Truncated => write!(f, "bytecode file is truncated"),
+            BytecodeError::Corrupted(reason) => write!(f, "bytecode file is corrupted: {}", reason),
+            BytecodeError::InvalidSourceSpan(source) => write!(
+                f,
+                "bytecode file has invalid source span {}..{}",
+                source.start, source.end
+            ),
+            BytecodeError::TooLargeSource(source_len) => write!(
+                f,
+                "bytecode file without source spans has {} BF instructions, at most {} are allowed",
+                source_len, MAX_SOURCE_LEN_WITHOUT_SPANS
+            ),
+        }
+    }
+}
+
+impl Error for BytecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BytecodeError::IOError(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BytecodeError {
+    fn from(err: io::Error) -> Self {
+        BytecodeError::IOError(err)
+    }
+}
+
+/// Write the program compiled for the cell type into bytecode, with the source-span table if `spans`.
+pub fn write(
+    program: &Program,
+    cell: CellWidth,
+    spans: bool,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&MAGIC);
+    buffer.extend_from_slice(&VERSION.to_le_bytes());
+    buffer.push(cell.bytes);
+    buffer.push(u8::from(cell.signed));
+    buffer.push(program.level() as u8);
+    buffer.push(if spans { SPANS_FLAG } else { 0 });
+    write_unsigned(&mut buffer, program.source_len() as u64);
+
+    let filename = program.filename().to_string_lossy();
+    write_unsigned(&mut buffer, filename.len() as u64);
+    buffer.extend_from_slice(filename.as_bytes());
+    write_instructions(&mut buffer, program.instructions());
+    if spans {
+        for location in program.locations() {
+            write_unsigned(&mut buffer, location.line as u64);
+            write_unsigned(&mut buffer, location.position as u64);
+            write_unsigned(&mut buffer, location.start as u64);
+            write_unsigned(&mut buffer, location.length as u64);
+        }
+    }
+    writer.write_all(&buffer)
+}
+
+/// Read the program and the header of bytecode file.
+pub fn read(reader: &mut impl Read) -> Result<(Header, Program), BytecodeError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let mut decoder = Decoder {
+        bytes: &bytes,
+        position: 0,
+    };
+
+    if decoder.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(BytecodeError::InvalidMagic);
+    }
+    let version = u16::from_le_bytes([decoder.byte()?, decoder.byte()?]);
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let cell_bytes = decoder.byte()?;
+    if !matches!(cell_bytes, 1 | 2 | 4 | 8) {
+        return Err(BytecodeError::InvalidCellWidth(cell_bytes));
+    }
+    let signed = match decoder.byte()? {
+        0 => false,
+        1 => true,
+        _ => return Err(corrupted("invalid cell sign")),
+    };
+    let level = match decoder.byte()? {
+        0 => OptimizationLevel::None,
+        1 => OptimizationLevel::Coalesce,
+        2 => OptimizationLevel::Idioms,
+        3 => OptimizationLevel::Offsets,
+        level => return Err(BytecodeError::InvalidOptimizationLevel(level)),
+    };
+    let flags = decoder.byte()?;
+    if flags & !SPANS_FLAG != 0 {
+        return Err(corrupted("unknown header flags"));
+    }
+    let header = Header {
+        version,
+        cell: CellWidth {
+            bytes: cell_bytes,
+            signed,
+        },
+        level,
+        spans: flags & SPANS_FLAG != 0,
+        source_len: decoder.length()?,
+    };
+    // The source-span table bounds the number of BF instructions by the size of the file.
+    if !header.spans && header.source_len > MAX_SOURCE_LEN_WITHOUT_SPANS {
+        return Err(BytecodeError::TooLargeSource(header.source_len));
+    }
+
+    let filename_length = decoder.length()?;
+    let filename = String::from_utf8_lossy(decoder.bytes(filename_length)?).into_owned();
+    let instructions = decoder.instructions(false)?;
+    let mut locations = Vec::new();
+    if header.spans {
+        for _ in 0..header.source_len {
+            locations.push(SourceLocation {
+                line: decoder.length()?,
+                position: decoder.length()?,
+                start: decoder.length()?,
+                length: decoder.length()?,
+            });
+        }
+    }
+    if decoder.position != bytes.len() {
+        return Err(corrupted("unexpected data after the program"));
+    }
+    validate(&instructions, header.source_len)?;
+
+    let program = Program::from_parts(
+        PathBuf::from(filename),
+        instructions,
+        locations,
+        header.source_len,
+        level,
+    );
+    Ok((header, program))
+}
+
+/// Write list of operations prefixed by its length.
+pub(crate) fn write_instructions(buffer: &mut Vec<u8>, instructions: &[Instruction]) {
+    write_unsigned(buffer, instructions.len() as u64);
+    for instruction in instructions {
+        match instruction.op() {
+            Op::Add { offset, delta } => {
+                buffer.push(0);
+                write_signed(buffer, *offset as i64);
+                write_signed(buffer, *delta);
+            }
+            Op::Move(delta) => {
+                buffer.push(1);
+                write_signed(buffer, *delta as i64);
+            }
+            Op::Output { offset } => {
+                buffer.push(2);
+                write_signed(buffer, *offset as i64);
+            }
+            Op::Input { offset } => {
+                buffer.push(3);
+                write_signed(buffer, *offset as i64);
+            }
+            Op::JumpIfZero(target) => {
+                buffer.push(4);
+                write_unsigned(buffer, *target as u64);
+            }
+            Op::JumpIfNonZero(target) => {
+                buffer.push(5);
+                write_unsigned(buffer, *target as u64);
+            }
+            Op::Clear(step) => {
+                buffer.push(6);
+                write_signed(buffer, *step);
+            }
+            Op::Scan(step) => {
+                buffer.push(7);
+                write_signed(buffer, *step as i64);
+            }
+            Op::MultiplyAdd(step, targets, (lowest, highest)) => {
+                buffer.push(8);
+                write_signed(buffer, *step);
+                write_signed(buffer, *lowest as i64);
+                write_signed(buffer, *highest as i64);
+                write_unsigned(buffer, targets.len() as u64);
+                for &(offset, factor) in targets {
+                    write_signed(buffer, offset as i64);
+                    write_signed(buffer, factor);
+                }
+            }
+            Op::Block(block) => {
+                buffer.push(9);
+                let (lowest, highest) = block.reach();
+                write_signed(buffer, block.shift() as i64);
+                write_signed(buffer, lowest as i64);
+                write_signed(buffer, highest as i64);
+                write_instructions(buffer, block.instructions());
+                write_instructions(buffer, block.fallback());
+            }
+            Op::DebugDump => buffer.push(10),
+        }
+        let source = instruction.source();
+        write_unsigned(buffer, source.start as u64);
+        write_unsigned(buffer, (source.end - source.start) as u64);
+    }
+}
+
+/// Write unsigned number as LEB128 variable-length integer.
+pub(crate) fn write_unsigned(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Write signed number as zigzag encoded LEB128 variable-length integer.
+pub(crate) fn write_signed(buffer: &mut Vec<u8>, value: i64) {
+    write_unsigned(buffer, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+/// Create error for malformed program.
+fn corrupted(reason: &str) -> BytecodeError {
+    BytecodeError::Corrupted(reason.to_string())
+}
+
+/// Provide structure to read values of bytecode one by one.
+pub(crate) struct Decoder<'a> {
+    /// Content of bytecode file.
+    pub(crate) bytes: &'a [u8],
+    /// Index of the next byte to read.
+    pub(crate) position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Read the next `count` bytes.
+    pub(crate) fn bytes(&mut self, count: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(BytecodeError::Truncated)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// Read the next byte.
+    pub(crate) fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Read LEB128 variable-length integer.
+    pub(crate) fn unsigned(&mut self) -> Result<u64, BytecodeError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if (bits << shift) >> shift != bits {
+                return Err(corrupted("too large number"));
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(corrupted("too large number"))
+    }
+
+    /// Read zigzag encoded LEB128 variable-length integer.
+    pub(crate) fn signed(&mut self) -> Result<i64, BytecodeError> {
+        let value = self.unsigned()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Read unsigned number fitting `usize`.
+    pub(crate) fn length(&mut self) -> Result<usize, BytecodeError> {
+        usize::try_from(self.unsigned()?).map_err(|_| corrupted("too large number"))
+    }
+
+    /// Read signed number fitting `isize`.
+    fn offset(&mut self) -> Result<isize, BytecodeError> {
+        isize::try_from(self.signed()?).map_err(|_| corrupted("too large offset"))
+    }
+
+    /// Read list of operations prefixed by its length, of the block if `in_block`.
+    fn instructions(&mut self, in_block: bool) -> Result<Vec<Instruction>, BytecodeError> {
+        let count = self.length()?;
+        // Every operation takes at least 3 bytes, so a wrong count can't allocate too much memory.
+        let mut instructions = Vec::with_capacity(count.min(self.bytes.len() / 3));
+        for _ in 0..count {
+            let op = match self.byte()? {
+                0 => Op::Add {
+                    offset: self.offset()?,
+                    delta: self.signed()?,
+                },
+                1 => Op::Move(self.offset()?),
+                2 => Op::Output {
+                    offset: self.offset()?,
+                },
+                3 => Op::Input {
+                    offset: self.offset()?,
+                },
+                4 => Op::JumpIfZero(self.length()?),
+                5 => Op::JumpIfNonZero(self.length()?),
+                6 => Op::Clear(self.signed()?),
+                7 => Op::Scan(self.offset()?),
+                8 => {
+                    let step = self.signed()?;
+                    let reach = (self.offset()?, self.offset()?);
+                    let count = self.length()?;
+                    let mut targets = Vec::with_capacity(count.min(self.bytes.len() / 2));
+                    for _ in 0..count {
+                        targets.push((self.offset()?, self.signed()?));
+                    }
+                    Op::MultiplyAdd(step, targets, reach)
+                }
+                // Blocks are never nested, so reading them can't recurse deeper than one level.
+                9 if in_block => return Err(corrupted("block inside block")),
+                9 => {
+                    let shift = self.offset()?;
+                    let reach = (self.offset()?, self.offset()?);
+                    let instructions = self.instructions(true)?;
+                    let fallback = self.instructions(true)?;
+                    Op::Block(Box::new(Block::from_parts(
+                        instructions,
+                        shift,
+                        reach,
+                        fallback,
+                    )))
+                }
+                10 => Op::DebugDump,
+                tag => {
+                    return Err(BytecodeError::Corrupted(format!(
+                        "unknown operation {}",
+                        tag
+                    )))
+                }
+            };
+            let start = self.length()?;
+            let end = start
+                .checked_add(self.length()?)
+                .ok_or_else(|| corrupted("too large source span"))?;
+            instructions.push(Instruction::new(op, start..end));
+        }
+        Ok(instructions)
+    }
+}
+
+/// Check that the Virtual Machine can execute operations, where `source_len` is the number of BF
+/// instructions of the source program.
+fn validate(instructions: &[Instruction], source_len: usize) -> Result<(), BytecodeError> {
+    for (index, instruction) in instructions.iter().enumerate() {
+        let minimum = match instruction.op() {
+            Op::Clear(_) | Op::Scan(_) | Op::MultiplyAdd(..) => IDIOM_SPAN,
+            _ => 1,
+        };
+        check_source(instruction.source(), minimum, source_len)?;
+        let valid = match *instruction.op() {
+            Op::Add { offset, .. } | Op::Output { offset } | Op::Input { offset } => offset == 0,
+            Op::Move(_) | Op::DebugDump => true,
+            Op::JumpIfZero(target) => matches!(
+                instructions.get(target).map(Instruction::op),
+                Some(&Op::JumpIfNonZero(open)) if open == index
+            ),
+            Op::JumpIfNonZero(target) => matches!(
+                instructions.get(target).map(Instruction::op),
+                Some(&Op::JumpIfZero(close)) if close == index
+            ),
+            Op::Clear(_) | Op::Scan(_) | Op::MultiplyAdd(..) => {
+                let idiom_step = match *instruction.op() {
+                    Op::Scan(step) => step != 0,
+                    // The tape is checked only for the cells the loop visits.
+                    Op::MultiplyAdd(_, ref targets, (lowest, highest)) => {
+                        let inside = |offset: isize| lowest <= offset && offset <= highest;
+                        inside(0) && targets.iter().all(|&(offset, _)| inside(offset))
+                    }
+                    _ => true,
+                };
+                idiom_step
+                    && matches!(
+                        instructions.get(index + 1).map(Instruction::op),
+                        Some(Op::JumpIfZero(_))
+                    )
+            }
+            Op::Block(ref block) => {
+                validate_block(block, source_len)?;
+                true
+            }
+        };
+        if !valid {
+            return Err(BytecodeError::Corrupted(format!(
+                "invalid operation {} at index {}",
+                instruction.op(),
+                index
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check that operations of the block stay inside the cells it passes.
+fn validate_block(block: &Block, source_len: usize) -> Result<(), BytecodeError> {
+    let (lowest, highest) = block.reach();
+    let inside = |offset: isize| lowest <= offset && offset <= highest;
+    if !inside(0) || !inside(block.shift()) {
+        return Err(corrupted("invalid block bounds"));
+    }
+    for instruction in block.instructions() {
+        check_source(instruction.source(), 1, source_len)?;
+        match *instruction.op() {
+            Op::Add { offset, .. } | Op::Output { offset } | Op::Input { offset }
+                if inside(offset) => {}
+            _ => return Err(corrupted("invalid operation in block")),
+        }
+    }
+    for instruction in block.fallback() {
+        check_source(instruction.source(), 1, source_len)?;
+        match *instruction.op() {
+            Op::Add { offset: 0, .. }
+            | Op::Output { offset: 0 }
+            | Op::Input { offset: 0 }
+            | Op::Move(_) => {}
+            _ => return Err(corrupted("invalid operation in block")),
+        }
+    }
+    Ok(())
+}
+
+/// Check that the operation is built from at least `minimum` BF instructions of the source program.
+fn check_source(
+    source: Range<usize>,
+    minimum: usize,
+    source_len: usize,
+) -> Result<(), BytecodeError> {
+    if source.len() < minimum || source.end > source_len {
+        return Err(BytecodeError::InvalidSourceSpan(source));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{
+        read, write, BytecodeError, CellWidth, Header, MAGIC, MAX_SOURCE_LEN_WITHOUT_SPANS, VERSION,
+    };
+    use crate::ir::{Block, Instruction, Op, OptimizationLevel, Program};
+    use btf_types::BrainFuckProgram;
+    use std::str::FromStr;
+
+    /// Compile the program with the optimization level.
+    fn compile(content: &str, level: OptimizationLevel) -> Program {
+        let program = BrainFuckProgram::from_str(content)
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        Program::compile(&program, level)
+    }
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        let content = "++\n[->+>++<<]>[>]<<[-]>>>+<-.>,[>+<<<+>>-]";
+        let cell = CellWidth::of::<i32>();
+        for level in [
+            OptimizationLevel::None,
+            OptimizationLevel::Coalesce,
+            OptimizationLevel::Idioms,
+            OptimizationLevel::Offsets,
+        ] {
+            let program = compile(content, level);
+            let mut bytes = Vec::new();
+            write(&program, cell, true, &mut bytes).unwrap();
+            assert_eq!(&bytes[..4], &MAGIC);
+
+            let (header, loaded) = read(&mut &bytes[..]).unwrap();
+            assert_eq!(
+                header,
+                Header {
+                    version: VERSION,
+                    cell,
+                    level,
+                    spans: true,
+                    source_len: 41,
+                }
+            );
+            assert_eq!(loaded.filename(), program.filename());
+            assert_eq!(loaded.instructions(), program.instructions());
+            assert_eq!(loaded.locations(), program.locations());
+            assert_eq!(loaded.level(), level);
+        }
+    }
+
+    #[test]
+    fn test_bytecode_without_spans() {
+        let program = compile("+\n+>.", OptimizationLevel::Offsets);
+        let mut bytes = Vec::new();
+        write(&program, CellWidth::of::<u8>(), false, &mut bytes).unwrap();
+
+        let (header, loaded) = read(&mut &bytes[..]).unwrap();
+        assert!(!header.spans);
+        assert_eq!(header.cell.to_string(), "u8");
+        assert_eq!(loaded.instructions(), program.instructions());
+        assert!(loaded.locations().is_empty());
+        assert_eq!(loaded.location(2), Default::default());
+        assert_eq!(header.source_len, 4);
+        assert_eq!(loaded.source_len(), 4);
+
+        // Source of operations is bounded by the number of BF instructions even without spans.
+        let far = 1 << 40;
+        let corrupted = Program::from_parts(
+            program.filename().to_path_buf(),
+            vec![Instruction::new(
+                Op::Add {
+                    offset: 0,
+                    delta: 1,
+                },
+                far..far + 1,
+            )],
+            Vec::new(),
+            program.source_len(),
+            program.level(),
+        );
+        let mut bytes = Vec::new();
+        write(&corrupted, CellWidth::of::<u8>(), false, &mut bytes).unwrap();
+        assert!(matches!(
+            read(&mut &bytes[..]),
+            Err(BytecodeError::InvalidSourceSpan(span)) if span == (far..far + 1)
+        ));
+
+        // The number of BF instructions isn't taken from the file without spans as is.
+        let corrupted = Program::from_parts(
+            program.filename().to_path_buf(),
+            vec![Instruction::new(
+                Op::Add {
+                    offset: 0,
+                    delta: 1,
+                },
+                far - 1..far,
+            )],
+            Vec::new(),
+            far,
+            program.level(),
+        );
+        let mut bytes = Vec::new();
+        write(&corrupted, CellWidth::of::<u8>(), false, &mut bytes).unwrap();
+        assert!(bytes.len() < 40);
+        assert!(matches!(
+            read(&mut &bytes[..]),
+            Err(BytecodeError::TooLargeSource(source_len)) if source_len == far
+        ));
+        let mut bytes = Vec::new();
+        write(&corrupted, CellWidth::of::<u8>(), true, &mut bytes).unwrap();
+        assert!(matches!(
+            read(&mut &bytes[..]),
+            Err(BytecodeError::Truncated)
+        ));
+        let largest = Program::from_parts(
+            program.filename().to_path_buf(),
+            program.instructions().to_vec(),
+            Vec::new(),
+            MAX_SOURCE_LEN_WITHOUT_SPANS,
+            program.level(),
+        );
+        let mut bytes = Vec::new();
+        write(&largest, CellWidth::of::<u8>(), false, &mut bytes).unwrap();
+        assert!(read(&mut &bytes[..]).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_bytecode() {
+        let program = compile("+[->+<]>.", OptimizationLevel::Offsets);
+        let mut bytes = Vec::new();
+        write(&program, CellWidth::of::<u8>(), true, &mut bytes).unwrap();
+
+        assert!(matches!(
+            read(&mut &b"+[->+<]>."[..]),
+            Err(BytecodeError::InvalidMagic)
+        ));
+
+        let mut other_version = bytes.clone();
+        other_version[4] = 2;
+        assert!(matches!(
+            read(&mut &other_version[..]),
+            Err(BytecodeError::UnsupportedVersion(2))
+        ));
+
+        let mut other_cell = bytes.clone();
+        other_cell[6] = 3;
+        assert!(matches!(
+            read(&mut &other_cell[..]),
+            Err(BytecodeError::InvalidCellWidth(3))
+        ));
+
+        let mut other_level = bytes.clone();
+        other_level[8] = 7;
+        assert!(matches!(
+            read(&mut &other_level[..]),
+            Err(BytecodeError::InvalidOptimizationLevel(7))
+        ));
+
+        for length in 0..bytes.len() {
+            assert!(read(&mut &bytes[..length]).is_err());
+        }
+        assert!(matches!(
+            read(&mut &bytes[..bytes.len() - 1]),
+            Err(BytecodeError::Truncated)
+        ));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(
+            read(&mut &trailing[..]),
+            Err(BytecodeError::Corrupted(_))
+        ));
+
+        for index in 10..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= 0x55;
+            let _ = read(&mut &corrupted[..]);
+        }
+    }
+
+    #[test]
+    fn test_invalid_source_span_bytecode() {
+        let program = compile("+[-]", OptimizationLevel::Idioms);
+        assert!(matches!(program.instructions()[1].op(), Op::Clear(_)));
+        for (index, source) in [(1, 1..1), (1, 1..3), (0, 0..0), (2, 1..5)] {
+            let mut instructions = program.instructions().to_vec();
+            instructions[index] =
+                Instruction::new(instructions[index].op().clone(), source.clone());
+            let corrupted = Program::from_parts(
+                program.filename().to_path_buf(),
+                instructions,
+                program.locations().to_vec(),
+                program.source_len(),
+                program.level(),
+            );
+            let mut bytes = Vec::new();
+            write(&corrupted, CellWidth::of::<u8>(), true, &mut bytes).unwrap();
+            assert!(matches!(
+                read(&mut &bytes[..]),
+                Err(BytecodeError::InvalidSourceSpan(span)) if span == source
+            ));
+        }
+    }
+
+    #[test]
+    fn test_nested_block_bytecode() {
+        let program = compile("+>+<", OptimizationLevel::Offsets);
+        let Op::Block(block) = program.instructions()[0].op() else {
+            panic!("program is compiled into block");
+        };
+        let mut fallback = block.fallback().to_vec();
+        fallback.push(program.instructions()[0].clone());
+        let nested = Block::from_parts(
+            block.instructions().to_vec(),
+            block.shift(),
+            block.reach(),
+            fallback,
+        );
+        let corrupted = Program::from_parts(
+            program.filename().to_path_buf(),
+            vec![Instruction::new(
+                Op::Block(Box::new(nested)),
+                program.instructions()[0].source(),
+            )],
+            program.locations().to_vec(),
+            program.source_len(),
+            program.level(),
+        );
+        let mut bytes = Vec::new();
+        write(&corrupted, CellWidth::of::<u8>(), true, &mut bytes).unwrap();
+        assert!(matches!(
+            read(&mut &bytes[..]),
+            Err(BytecodeError::Corrupted(reason)) if reason == "block inside block"
+        ));
+
+        // Deeply nested blocks are rejected without recursion into them.
+        let mut bytes = Vec::new();
+        let empty = compile("", OptimizationLevel::Offsets);
+        write(&empty, CellWidth::of::<u8>(), false, &mut bytes).unwrap();
+        assert_eq!(bytes.pop(), Some(0));
+        bytes.push(1);
+        for _ in 0..200_000 {
+            bytes.extend_from_slice(&[9, 0, 0, 0, 1]);
+        }
+        assert!(matches!(
+            read(&mut &bytes[..]),
+            Err(BytecodeError::Corrupted(_))
+        ));
+    }
+}
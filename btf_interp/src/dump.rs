@@ -0,0 +1,119 @@
+//! Provide dump of the tape written by `#` instruction of the debug dump extension.
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Provide enum of formats of the tape dump.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DumpFormat {
+    /// One line like `#[1:5] head 2 cells 0..=4: 0 3 [7] 0 0`, the current cell in brackets. Default one.
+    #[default]
+    Text,
+    /// One JSON object per line with the location, the head, the first cell index and cell values.
+    Json,
+}
+
+/// Parse dump format from its command line name: `text` or `json`.
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<DumpFormat, Self::Err> {
+        match name {
+            "text" => Ok(DumpFormat::Text),
+            "json" => Ok(DumpFormat::Json),
+            _ => Err(format!(
+                "unknown dump format {}, expected one of: text, json",
+                name
+            )),
+        }
+    }
+}
+
+/// Provide structure to write the tape around the head when `#` instruction is executed.
+pub struct TapeDump {
+    /// Destination of dumps.
+    writer: Box<dyn Write>,
+    /// Format of every dump.
+    format: DumpFormat,
+    /// The number of cells dumped at each side of the head.
+    radius: usize,
+}
+
+impl fmt::Debug for TapeDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TapeDump")
+            .field("format", &self.format)
+            .field("radius", &self.radius)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Write text dumps of 8 cells at each side of the head into stderr by default.
+impl Default for TapeDump {
+    fn default() -> Self {
+        TapeDump::new(io::stderr(), DumpFormat::default(), 8)
+    }
+}
+
+impl TapeDump {
+    /// Create dump writing `radius` cells at each side of the head into `writer` in the format.
+    pub fn new(writer: impl Write + 'static, format: DumpFormat, radius: usize) -> TapeDump {
+        TapeDump {
+            writer: Box::new(writer),
+            format,
+            radius,
+        }
+    }
+
+    /// Get the number of cells dumped at each side of the head.
+    pub fn radius(&self) -> usize {
+        self.radius
+    }
+
+    /// Write cells starting at index `start` with the head and the location of `#` instruction.
+    pub(crate) fn write(
+        &mut self,
+        location: (usize, usize),
+        head: usize,
+        start: usize,
+        cells: &[i128],
+    ) -> io::Result<()> {
+        let (line, position) = location;
+        match self.format {
+            DumpFormat::Text => {
+                write!(
+                    self.writer,
+                    "#[{}:{}] head {} cells {}..={}:",
+                    line,
+                    position,
+                    head,
+                    start,
+                    start + cells.len().saturating_sub(1)
+                )?;
+                for (index, value) in (start..).zip(cells) {
+                    if index == head {
+                        write!(self.writer, " [{}]", value)?;
+                    } else {
+                        write!(self.writer, " {}", value)?;
+                    }
+                }
+                writeln!(self.writer)?;
+            }
+            DumpFormat::Json => {
+                write!(
+                    self.writer,
+                    "{{\"line\":{},\"position\":{},\"head\":{},\"start\":{},\"cells\":[",
+                    line, position, head, start
+                )?;
+                for (index, value) in cells.iter().enumerate() {
+                    if index > 0 {
+                        write!(self.writer, ",")?;
+                    }
+                    write!(self.writer, "{}", value)?;
+                }
+                writeln!(self.writer, "]}}")?;
+            }
+        }
+        self.writer.flush()
+    }
+}
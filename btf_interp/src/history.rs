@@ -0,0 +1,110 @@
+//! Provide history of executed operations to execute BF program backwards.
+use std::collections::VecDeque;
+
+/// Provide structure to represent the state an operation changed, to revert it.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Change {
+    /// The index of the operation.
+    pub(crate) instruction_pointer: usize,
+    /// The pointer to the current element of tape before the operation.
+    pub(crate) head: usize,
+    /// The number of BF instructions executed before the operation.
+    pub(crate) steps: u64,
+    /// The progress of the operation interrupted by the step limit before the operation.
+    pub(crate) resume_source: Option<usize>,
+    /// The size of the tape before the operation.
+    pub(crate) tape_size: usize,
+    /// Indices of cells written by the operation with two's complement bits of their values before
+    /// the operation, in order of the first write of every cell.
+    pub(crate) writes: Vec<(usize, u64)>,
+    /// Bytes of input read by the operation.
+    pub(crate) input: Vec<u8>,
+}
+
+/// Provide structure to represent the write of the cell found in the history.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CellWrite {
+    /// The number of BF instructions executed before the operation which wrote the cell.
+    pub step: u64,
+    /// The index of the operation which wrote the cell.
+    pub instruction_pointer: usize,
+    /// Value of the cell before the write.
+    pub before: i128,
+    /// Value of the cell after the write.
+    pub after: i128,
+}
+
+/// Provide ring buffer of changes of the last executed operations, the oldest is dropped first.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct History {
+    /// Changes of operations, the latest last.
+    changes: VecDeque<Change>,
+    /// The maximum number of changes kept.
+    capacity: usize,
+}
+
+impl History {
+    /// Create empty history keeping up to `capacity` changes.
+    pub(crate) fn new(capacity: usize) -> Self {
+        History {
+            changes: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Get the number of operations which can be reverted.
+    pub(crate) fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Start recording the change of the operation, dropping the oldest one if the history is full.
+    pub(crate) fn push(&mut self, change: Change) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.changes.len() == self.capacity {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Take the change of the latest operation to revert it.
+    pub(crate) fn pop(&mut self) -> Option<Change> {
+        self.changes.pop_back()
+    }
+
+    /// Record the write of the cell at `index` holding value of `before` bits by the latest operation.
+    /// Only the first write of the cell is recorded, the later ones write over the operation's own
+    /// values, e.g. in block adding to the same cell twice.
+    pub(crate) fn record_write(&mut self, index: usize, before: u64) {
+        if let Some(change) = self.changes.back_mut() {
+            if change.writes.iter().all(|&(written, _)| written != index) {
+                change.writes.push((index, before));
+            }
+        }
+    }
+
+    /// Record bytes of input read by the latest operation.
+    pub(crate) fn record_input(&mut self, bytes: &[u8]) {
+        if let Some(change) = self.changes.back_mut() {
+            change.input.extend_from_slice(bytes);
+        }
+    }
+
+    /// Forget all changes, e.g. when the state is changed not by the program.
+    pub(crate) fn clear(&mut self) {
+        self.changes.clear();
+    }
+
+    /// Find the latest operation which wrote the cell at `index` with bits of the value before it.
+    pub(crate) fn last_write(&self, index: usize) -> Option<(&Change, u64)> {
+        self.changes.iter().rev().find_map(|change| {
+            change
+                .writes
+                .iter()
+                .rev()
+                .find(|&&(written, _)| written == index)
+                .map(|&(_, before)| (change, before))
+        })
+    }
+}
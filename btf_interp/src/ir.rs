@@ -0,0 +1,689 @@
+//! Provide intermediate representation of BF program executed by Virtual Machine.
+use btf_types::{IntructionPosition, RawInstructions, ValidatedProgram};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Provide enum of optimizations applied while building intermediate representation.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum OptimizationLevel {
+    /// One operation for every BF instruction.
+    None,
+    /// Fold runs of the same `+`, `-`, `>` or `<` instruction into a single operation.
+    Coalesce,
+    /// Also replace clear, scan and multiply-add loops with a single operation.
+    Idioms,
+    /// Also address cells relative to the head inside straight-line blocks instead of moving it.
+    Offsets,
+}
+
+/// Parse optimization level from its number: `0`, `1`, `2` or `3`.
+impl FromStr for OptimizationLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<OptimizationLevel, Self::Err> {
+        match level {
+            "0" => Ok(OptimizationLevel::None),
+            "1" => Ok(OptimizationLevel::Coalesce),
+            "2" => Ok(OptimizationLevel::Idioms),
+            "3" => Ok(OptimizationLevel::Offsets),
+            _ => Err(format!(
+                "unknown optimization level {}, expected one of: 0, 1, 2, 3",
+                level
+            )),
+        }
+    }
+}
+
+/// Provide enum of operations of intermediate representation.
+///
+/// Loop idiom operations (`Clear`, `Scan` and `MultiplyAdd`) are followed by the loop they are built
+/// from. When the idiom can't give the same result as the loop, e.g. it would go past the bound of the
+/// cell value or of the tape, Virtual Machine executes the loop itself, otherwise it skips the loop.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Op {
+    /// Add `delta` to the cell at `offset` from the head. Built from a run of `+` or `-`.
+    Add { offset: isize, delta: i64 },
+    /// Move the head by the number of cells, to the right if positive. Built from a run of `>` or `<`.
+    Move(isize),
+    /// Output the cell at `offset` from the head. Built from `.`.
+    Output { offset: isize },
+    /// Read input into the cell at `offset` from the head. Built from `,`.
+    Input { offset: isize },
+    /// Jump to the operation at index if the current cell is zero. Built from `[`.
+    JumpIfZero(usize),
+    /// Jump to the operation at index if the current cell is not zero. Built from `]`.
+    JumpIfNonZero(usize),
+    /// Set the current cell to zero. Built from `[-]` or `[+]`, holds the value the loop adds.
+    Clear(i64),
+    /// Move the head by the number of cells until it points to zero cell. Built from `[>]` or `[<]`.
+    Scan(isize),
+    /// Add the current cell multiplied by factor to the cell at offset for every pair, then set the
+    /// current cell to zero. Built from balanced loops like `[->+>++<<]` which add -1 or 1 to the
+    /// current cell, holds that value, the pairs and the lowest and the highest offsets the loop
+    /// visits, including cells it leaves unchanged.
+    MultiplyAdd(i64, Vec<(isize, i64)>, (isize, isize)),
+    /// Execute straight-line operations addressed relative to the head. Built from a sequence of
+    /// `Add`, `Move`, `Output` and `Input` operations.
+    Block(Box<Block>),
+    /// Dump the tape around the head. Built from `#` of the debug dump extension.
+    DebugDump,
+}
+
+/// Provide human-readable format of the operation.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Add { offset: 0, delta } => write!(f, "Add {}", delta),
+            Op::Add { offset, delta } => write!(f, "Add {} at {}", delta, offset),
+            Op::Move(delta) => write!(f, "Move {}", delta),
+            Op::Output { offset: 0 } => write!(f, "Output"),
+            Op::Output { offset } => write!(f, "Output at {}", offset),
+            Op::Input { offset: 0 } => write!(f, "Input"),
+            Op::Input { offset } => write!(f, "Input at {}", offset),
+            Op::JumpIfZero(target) => write!(f, "Jump to {} if zero", target),
+            Op::JumpIfNonZero(target) => write!(f, "Jump to {} if non zero", target),
+            Op::Clear(_step) => write!(f, "Clear"),
+            Op::Scan(step) => write!(f, "Scan {}", step),
+            Op::MultiplyAdd(_step, targets, _reach) => {
+                write!(f, "Multiply-add")?;
+                for (offset, factor) in targets {
+                    write!(f, " {}*{}", offset, factor)?;
+                }
+                Ok(())
+            }
+            Op::Block(block) => {
+                write!(f, "Block")?;
+                for instruction in block.instructions() {
+                    write!(f, " [{}]", instruction.op())?;
+                }
+                write!(f, " then move {}", block.shift())
+            }
+            Op::DebugDump => write!(f, "Debug dump"),
+        }
+    }
+}
+
+/// Provide structure to represent straight-line operations addressed relative to the head.
+///
+/// When cells the head passes inside the block are out of the tape, the block is executed as the
+/// operations it is built from, so the tape grows or the error is reported at the right instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    /// `Add`, `Output` and `Input` operations with offsets relative to the head at the block start.
+    instructions: Vec<Instruction>,
+    /// The number of cells the head moves at the end of the block.
+    shift: isize,
+    /// The lowest offset the head passes inside the block.
+    lowest: isize,
+    /// The highest offset the head passes inside the block.
+    highest: isize,
+    /// Operations the block is built from.
+    fallback: Vec<Instruction>,
+}
+
+impl Block {
+    /// Create block from already built operations.
+    pub(crate) fn from_parts(
+        instructions: Vec<Instruction>,
+        shift: isize,
+        reach: (isize, isize),
+        fallback: Vec<Instruction>,
+    ) -> Block {
+        Block {
+            instructions,
+            shift,
+            lowest: reach.0,
+            highest: reach.1,
+            fallback,
+        }
+    }
+
+    /// Build block from straight-line operations. Return `None` if it contains no moves to remove.
+    fn build(fallback: Vec<Instruction>) -> Option<Block> {
+        let moves = fallback
+            .iter()
+            .filter(|instruction| matches!(instruction.op, Op::Move(_)))
+            .count();
+        if moves == 0 || moves == fallback.len() {
+            return None;
+        }
+
+        let mut instructions = Vec::new();
+        let mut offset: isize = 0;
+        let mut lowest: isize = 0;
+        let mut highest: isize = 0;
+        for instruction in &fallback {
+            let op = match instruction.op {
+                Op::Move(delta) => {
+                    offset += delta;
+                    lowest = lowest.min(offset);
+                    highest = highest.max(offset);
+                    continue;
+                }
+                Op::Add { delta, .. } => Op::Add { offset, delta },
+                Op::Output { .. } => Op::Output { offset },
+                Op::Input { .. } => Op::Input { offset },
+                _ => unreachable!("only straight-line operations are in the block"),
+            };
+            instructions.push(Instruction {
+                op,
+                source: instruction.source(),
+            });
+        }
+        Some(Block {
+            instructions,
+            shift: offset,
+            lowest,
+            highest,
+            fallback,
+        })
+    }
+
+    /// Get operations with offsets relative to the head at the block start.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions[..]
+    }
+
+    /// Get the number of cells the head moves at the end of the block.
+    pub fn shift(&self) -> isize {
+        self.shift
+    }
+
+    /// Get the lowest and the highest offsets the head passes inside the block.
+    pub fn reach(&self) -> (isize, isize) {
+        (self.lowest, self.highest)
+    }
+
+    /// Get operations the block is built from.
+    pub fn fallback(&self) -> &[Instruction] {
+        &self.fallback[..]
+    }
+}
+
+/// Provide structure to represent operation with the BF instructions it is built from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    /// Operation to execute.
+    op: Op,
+    /// Indices of BF instructions of the source program the operation is built from.
+    source: Range<usize>,
+}
+
+impl Instruction {
+    /// Create operation built from BF instructions of the source program at indices `source`.
+    pub(crate) fn new(op: Op, source: Range<usize>) -> Instruction {
+        Instruction { op, source }
+    }
+
+    /// Get operation to execute.
+    pub fn op(&self) -> &Op {
+        &self.op
+    }
+
+    /// Get indices of BF instructions of the source program the operation is built from.
+    pub fn source(&self) -> Range<usize> {
+        self.source.clone()
+    }
+}
+
+/// Provide structure to represent location of BF instruction in file.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SourceLocation {
+    /// Line of the file from where BF instruction is parsed.
+    pub line: usize,
+    /// Position at the line from where BF instruction is parsed.
+    pub position: usize,
+    /// Offset in bytes from the start of the file from where BF instruction is parsed.
+    pub start: usize,
+    /// Length in bytes of BF instruction in the file.
+    pub length: usize,
+}
+
+/// Provide structure to represent BF program in intermediate representation.
+#[derive(Debug)]
+pub struct Program {
+    /// Name of the file from where program is parsed.
+    filename: PathBuf,
+    /// List of operations.
+    instructions: Vec<Instruction>,
+    /// Location of every BF instruction of the source program.
+    locations: Vec<SourceLocation>,
+    /// The number of BF instructions of the source program.
+    source_len: usize,
+    /// Content of the source file, unknown for the program loaded from bytecode.
+    source: Option<Vec<u8>>,
+    /// Optimizations applied to the program.
+    level: OptimizationLevel,
+}
+
+impl Program {
+    /// Create program from already built operations.
+    pub(crate) fn from_parts(
+        filename: PathBuf,
+        instructions: Vec<Instruction>,
+        locations: Vec<SourceLocation>,
+        source_len: usize,
+        level: OptimizationLevel,
+    ) -> Program {
+        Program {
+            filename,
+            instructions,
+            locations,
+            source_len,
+            source: None,
+            level,
+        }
+    }
+
+    /// Build intermediate representation of validated BF program.
+    pub fn compile(program: &ValidatedProgram, level: OptimizationLevel) -> Program {
+        let source = program.instructions();
+        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut opened_loops: Vec<usize> = Vec::new();
+        let mut index = 0;
+        while index < source.len() {
+            let instruction = source[index].instruction();
+            let mut end = index + 1;
+            if level >= OptimizationLevel::Coalesce && is_foldable(instruction) {
+                while end < source.len() && source[end].instruction() == instruction {
+                    end += 1;
+                }
+            }
+            let count = end - index;
+            let op = match instruction {
+                RawInstructions::IncrementByte => Op::Add {
+                    offset: 0,
+                    delta: count as i64,
+                },
+                RawInstructions::DecrementByte => Op::Add {
+                    offset: 0,
+                    delta: -(count as i64),
+                },
+                RawInstructions::IncrementDataPointer => Op::Move(count as isize),
+                RawInstructions::DecrementDataPointer => Op::Move(-(count as isize)),
+                RawInstructions::OutputByte => Op::Output { offset: 0 },
+                RawInstructions::AcceptByte => Op::Input { offset: 0 },
+                RawInstructions::DebugDump => Op::DebugDump,
+                RawInstructions::ZeroJump => {
+                    if level >= OptimizationLevel::Idioms {
+                        let close = program
+                            .matching_bracket(index)
+                            .expect("brackets of validated program are matched");
+                        if let Some(op) = loop_idiom(&source[index + 1..close]) {
+                            instructions.push(Instruction {
+                                op,
+                                source: index..close + 1,
+                            });
+                        }
+                    }
+                    opened_loops.push(instructions.len());
+                    Op::JumpIfZero(0)
+                }
+                RawInstructions::NonZeroJump => {
+                    let open = opened_loops
+                        .pop()
+                        .expect("brackets of validated program are matched");
+                    instructions[open].op = Op::JumpIfZero(instructions.len());
+                    Op::JumpIfNonZero(open)
+                }
+            };
+            instructions.push(Instruction {
+                op,
+                source: index..end,
+            });
+            index = end;
+        }
+
+        if level >= OptimizationLevel::Offsets {
+            instructions = offset_blocks(instructions);
+        }
+
+        Program {
+            filename: program.filename().to_path_buf(),
+            instructions,
+            locations: source
+                .iter()
+                .map(|instruction| {
+                    let span = instruction.span();
+                    SourceLocation {
+                        line: span.line,
+                        position: span.column,
+                        start: span.start,
+                        length: span.length,
+                    }
+                })
+                .collect(),
+            source_len: source.len(),
+            source: Some(program.source().to_vec()),
+            level,
+        }
+    }
+
+    /// Get name of the file from where program is parsed.
+    pub fn filename(&self) -> &Path {
+        &self.filename
+    }
+
+    /// Get content of the source file, if it is known.
+    pub fn source(&self) -> Option<&[u8]> {
+        self.source.as_deref()
+    }
+
+    /// Get list of operations.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions[..]
+    }
+
+    /// Get location of BF instruction of the source program by its index. It is line 0 and column 0
+    /// if the program is loaded without locations.
+    pub fn location(&self, index: usize) -> SourceLocation {
+        self.locations.get(index).copied().unwrap_or_default()
+    }
+
+    /// Get location of every BF instruction of the source program.
+    pub fn locations(&self) -> &[SourceLocation] {
+        &self.locations[..]
+    }
+
+    /// Get the number of BF instructions of the source program, known even without locations.
+    pub fn source_len(&self) -> usize {
+        self.source_len
+    }
+
+    /// Get optimizations applied to the program.
+    pub fn level(&self) -> OptimizationLevel {
+        self.level
+    }
+}
+
+/// Check if runs of the instruction can be folded into a single operation.
+fn is_foldable(instruction: &RawInstructions) -> bool {
+    matches!(
+        instruction,
+        RawInstructions::IncrementByte
+            | RawInstructions::DecrementByte
+            | RawInstructions::IncrementDataPointer
+            | RawInstructions::DecrementDataPointer
+    )
+}
+
+/// Replace sequences of straight-line operations with blocks and update jump targets.
+fn offset_blocks(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut result: Vec<Instruction> = Vec::new();
+    let mut new_indices: Vec<usize> = Vec::with_capacity(instructions.len());
+    let mut straight: Vec<Instruction> = Vec::new();
+    for instruction in instructions {
+        let is_straight = matches!(
+            instruction.op,
+            Op::Add { .. } | Op::Move(_) | Op::Output { .. } | Op::Input { .. }
+        );
+        // Jumps only target jumps, so only their new indices are exact.
+        if is_straight {
+            new_indices.push(result.len());
+            straight.push(instruction);
+        } else {
+            flush_block(&mut straight, &mut result);
+            new_indices.push(result.len());
+            result.push(instruction);
+        }
+    }
+    flush_block(&mut straight, &mut result);
+
+    for instruction in result.iter_mut() {
+        match instruction.op {
+            Op::JumpIfZero(ref mut target) | Op::JumpIfNonZero(ref mut target) => {
+                *target = new_indices[*target];
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Move straight-line operations into the block if it removes moves, or as they are otherwise.
+fn flush_block(straight: &mut Vec<Instruction>, result: &mut Vec<Instruction>) {
+    if straight.is_empty() {
+        return;
+    }
+    let operations = std::mem::take(straight);
+    let source = operations[0].source.start..operations[operations.len() - 1].source.end;
+    match Block::build(operations.clone()) {
+        Some(block) => result.push(Instruction {
+            op: Op::Block(Box::new(block)),
+            source,
+        }),
+        None => result.extend(operations),
+    }
+}
+
+/// Recognize loop idiom by the body of the loop.
+fn loop_idiom(body: &[IntructionPosition]) -> Option<Op> {
+    let mut offset: isize = 0;
+    let mut lowest: isize = 0;
+    let mut highest: isize = 0;
+    let mut deltas: Vec<(isize, i64)> = Vec::new();
+    for instruction_position in body {
+        match instruction_position.instruction() {
+            RawInstructions::IncrementDataPointer => {
+                offset += 1;
+                highest = highest.max(offset);
+            }
+            RawInstructions::DecrementDataPointer => {
+                offset -= 1;
+                lowest = lowest.min(offset);
+            }
+            RawInstructions::IncrementByte | RawInstructions::DecrementByte => {
+                let delta = match instruction_position.instruction() {
+                    RawInstructions::IncrementByte => 1,
+                    _ => -1,
+                };
+                match deltas.iter_mut().find(|(target, _)| *target == offset) {
+                    Some((_, factor)) => *factor += delta,
+                    None => deltas.push((offset, delta)),
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    if deltas.is_empty() {
+        if offset != 0 && offset.unsigned_abs() == body.len() {
+            return Some(Op::Scan(offset));
+        }
+        return None;
+    }
+    // Cells visited on the way to the changed ones have to be checked by the loop itself.
+    let lowest_target = deltas.iter().map(|&(target, _)| target).min().unwrap_or(0);
+    let highest_target = deltas.iter().map(|&(target, _)| target).max().unwrap_or(0);
+    if offset != 0 || lowest < lowest_target.min(0) || highest > highest_target.max(0) {
+        return None;
+    }
+    let step = match deltas.iter().find(|(target, _)| *target == 0) {
+        Some(&(_, step)) if step == 1 || step == -1 => step,
+        _ => return None,
+    };
+    deltas.retain(|&(target, factor)| target != 0 && factor != 0);
+    if deltas.is_empty() && body.len() == 1 {
+        Some(Op::Clear(step))
+    } else {
+        Some(Op::MultiplyAdd(step, deltas, (lowest, highest)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{Op, OptimizationLevel, Program, SourceLocation};
+    use btf_types::BrainFuckProgram;
+    use std::ops::Range;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_coalesce_program() {
+        let program = BrainFuckProgram::from_str("+++--\n[>>.<,]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let ir_program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let ops: Vec<&Op> = ir_program
+            .instructions()
+            .iter()
+            .map(|instruction| instruction.op())
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                &Op::Add {
+                    offset: 0,
+                    delta: 3
+                },
+                &Op::Add {
+                    offset: 0,
+                    delta: -2
+                },
+                &Op::JumpIfZero(7),
+                &Op::Move(2),
+                &Op::Output { offset: 0 },
+                &Op::Move(-1),
+                &Op::Input { offset: 0 },
+                &Op::JumpIfNonZero(2),
+            ]
+        );
+        let sources: Vec<_> = ir_program
+            .instructions()
+            .iter()
+            .map(|instruction| instruction.source())
+            .collect();
+        assert_eq!(
+            sources,
+            vec![0..3, 3..5, 5..6, 6..8, 8..9, 9..10, 10..11, 11..12]
+        );
+        assert_eq!(
+            ir_program.location(7),
+            SourceLocation {
+                line: 2,
+                position: 3,
+                start: 8,
+                length: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_no_optimization_program() {
+        let program = BrainFuckProgram::from_str("++>>")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let ir_program = Program::compile(&program, OptimizationLevel::None);
+
+        let ops: Vec<&Op> = ir_program
+            .instructions()
+            .iter()
+            .map(|instruction| instruction.op())
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                &Op::Add {
+                    offset: 0,
+                    delta: 1
+                },
+                &Op::Add {
+                    offset: 0,
+                    delta: 1
+                },
+                &Op::Move(1),
+                &Op::Move(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loop_idioms_program() {
+        let program = BrainFuckProgram::from_str("[-][>>][<][->+>++<<][->>><+<<]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let ir_program = Program::compile(&program, OptimizationLevel::Idioms);
+
+        let idioms: Vec<(&Op, Range<usize>)> = ir_program
+            .instructions()
+            .iter()
+            .filter(|instruction| {
+                matches!(
+                    instruction.op(),
+                    Op::Clear(_) | Op::Scan(_) | Op::MultiplyAdd(..)
+                )
+            })
+            .map(|instruction| (instruction.op(), instruction.source()))
+            .collect();
+        assert_eq!(
+            idioms,
+            vec![
+                (&Op::Clear(-1), 0..3),
+                (&Op::Scan(2), 3..7),
+                (&Op::Scan(-1), 7..10),
+                (&Op::MultiplyAdd(-1, vec![(1, 1), (2, 2)], (0, 2)), 10..20),
+            ]
+        );
+        assert_eq!(ir_program.instructions()[1].op(), &Op::JumpIfZero(3));
+    }
+
+    #[test]
+    fn test_offset_blocks_program() {
+        let program = BrainFuckProgram::from_str("+>++<.[>>-<<<]++")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let ir_program = Program::compile(&program, OptimizationLevel::Offsets);
+
+        let instructions = ir_program.instructions();
+        assert_eq!(instructions.len(), 5);
+        assert_eq!(instructions[0].source(), 0..6);
+        let block = match instructions[0].op() {
+            Op::Block(block) => block,
+            op => panic!("Expected block, found {}", op),
+        };
+        let ops: Vec<&Op> = block
+            .instructions()
+            .iter()
+            .map(|instruction| instruction.op())
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                &Op::Add {
+                    offset: 0,
+                    delta: 1
+                },
+                &Op::Add {
+                    offset: 1,
+                    delta: 2
+                },
+                &Op::Output { offset: 0 },
+            ]
+        );
+        assert_eq!(block.shift(), 0);
+        assert_eq!(block.reach(), (0, 1));
+        assert_eq!(block.fallback().len(), 5);
+        assert_eq!(instructions[1].op(), &Op::JumpIfZero(3));
+        match instructions[2].op() {
+            Op::Block(block) => {
+                assert_eq!(block.shift(), -1);
+                assert_eq!(block.reach(), (-1, 2));
+            }
+            op => panic!("Expected block, found {}", op),
+        }
+        assert_eq!(instructions[3].op(), &Op::JumpIfNonZero(1));
+        assert_eq!(
+            instructions[4].op(),
+            &Op::Add {
+                offset: 0,
+                delta: 2
+            }
+        );
+    }
+}
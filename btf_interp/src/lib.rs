@@ -1,49 +1,384 @@
 //! Provide interpreter implementation for BF program.
-use btf_types::BrainFuckProgram;
-use std::io::{Read, Write};
+pub mod bytecode;
+pub mod dump;
+pub mod history;
+pub mod ir;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
+
+use btf_types::diagnostic::{Diagnostic, Label};
+use bytecode::CellWidth;
+use dump::TapeDump;
+use history::{CellWrite, Change, History};
+use ir::{Block, Instruction, Op, Program};
+use profile::Profile;
+use snapshot::{Snapshot, SnapshotError};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
 use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use trace::{TraceRecord, Tracer};
+
+/// Number of operations Virtual Machine executes between checks of cancellation and deadline.
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
 
 /// Provide trait for cell in Virtual Machine.
-pub trait CellKind {
+pub trait CellKind: Default + Clone {
+    /// Type of the value stored in the cell.
+    type Value: Copy + Default + PartialEq + fmt::Display + Into<i128>;
+    /// Number of bytes occupied by the value of the cell.
+    const BYTES: usize;
+    /// Whether the value of the cell can be negative.
+    const SIGNED: bool;
+
     /// Wrapper to increase value by 1 in the cell.
     fn wrapping_increment(&mut self);
     /// Wrapper to decrease value by 1 in the cell.
     fn wrapping_decrement(&mut self);
+    /// Wrapper to add `delta` to the value in the cell.
+    fn wrapping_add_value(&mut self, delta: i64);
+    /// Add `delta` to the value in the cell, staying at the bound of the value it goes past.
+    fn saturating_add_value(&mut self, delta: i64);
+    /// Add `delta` to the value in the cell one by one. On going past the bound of the value stop at that
+    /// bound and return the number of performed steps.
+    fn checked_add_value(&mut self, delta: i64) -> Result<(), u64>;
     /// Wrapper to set value in the cell.
-    fn wrapping_set_value(&mut self, value: u8);
+    fn wrapping_set_value(&mut self, value: Self::Value);
     /// Wrapper to get value from the cell.
-    fn wrapping_get_value(&self) -> u8;
+    fn wrapping_get_value(&self) -> Self::Value;
+    /// Create value from the lowest `BYTES` bytes of `bits`.
+    fn value_from_bits(bits: u64) -> Self::Value;
+    /// Get two's complement bits of the value, sign extended to 64 bits.
+    fn value_to_bits(value: Self::Value) -> u64;
+}
+
+/// Provide implementation of integer types as cells in Virtual Machine.
+macro_rules! impl_cell_kind {
+    ($($cell:ty),*) => {
+        $(
+            /// Implementation for integer cell type, the value of the cell is the integer itself.
+            impl CellKind for $cell {
+                type Value = $cell;
+                const BYTES: usize = std::mem::size_of::<$cell>();
+                const SIGNED: bool = <$cell>::MIN != 0;
+
+                fn wrapping_increment(&mut self) {
+                    *self = self.wrapping_add(1);
+                }
+                fn wrapping_decrement(&mut self) {
+                    *self = self.wrapping_sub(1);
+                }
+                fn wrapping_add_value(&mut self, delta: i64) {
+                    *self = self.wrapping_add(delta as $cell);
+                }
+                fn saturating_add_value(&mut self, delta: i64) {
+                    let value = i128::from(*self) + i128::from(delta);
+                    *self = value.clamp(i128::from(<$cell>::MIN), i128::from(<$cell>::MAX)) as $cell;
+                }
+                fn checked_add_value(&mut self, delta: i64) -> Result<(), u64> {
+                    let value = i128::from(*self) + i128::from(delta);
+                    let bound = value.clamp(i128::from(<$cell>::MIN), i128::from(<$cell>::MAX));
+                    let steps = (bound - i128::from(*self)).unsigned_abs() as u64;
+                    *self = bound as $cell;
+                    if bound == value {
+                        Ok(())
+                    } else {
+                        Err(steps)
+                    }
+                }
+                fn wrapping_set_value(&mut self, value: $cell) {
+                    *self = value;
+                }
+                fn wrapping_get_value(&self) -> $cell {
+                    *self
+                }
+                fn value_from_bits(bits: u64) -> $cell {
+                    bits as $cell
+                }
+                fn value_to_bits(value: $cell) -> u64 {
+                    value as u64
+                }
+            }
+        )*
+    };
+}
+
+impl_cell_kind!(u8, u16, u32, u64, i8, i32);
+
+/// Provide enum of policies for `+` and `-` instructions going past the bounds of the cell value.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OverflowPolicy {
+    /// Wrap around to the other bound of the value. Default one.
+    #[default]
+    Wrap,
+    /// Stay at the bound of the value.
+    Saturate,
+    /// Stop the program with `VMError::CellOverflow` or `VMError::CellUnderflow`.
+    Trap,
+}
+
+/// Parse overflow policy from its command line name: `wrap`, `saturate` or `trap`.
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<OverflowPolicy, Self::Err> {
+        match name {
+            "wrap" => Ok(OverflowPolicy::Wrap),
+            "saturate" => Ok(OverflowPolicy::Saturate),
+            "trap" => Ok(OverflowPolicy::Trap),
+            _ => Err(format!(
+                "unknown overflow policy {}, expected one of: wrap, saturate, trap",
+                name
+            )),
+        }
+    }
+}
+
+/// Provide enum of policies for `,` instruction when there is no more input.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum EofPolicy {
+    /// Leave the cell unchanged. Default one.
+    #[default]
+    Unchanged,
+    /// Store 0 in the cell.
+    Zero,
+    /// Store -1 in the cell, which is the maximum value for unsigned cells.
+    MinusOne,
+    /// Stop the program with `VMError::EndOfInput`.
+    Error,
+}
+
+/// Parse end of input policy from its command line name: `unchanged`, `zero`, `minus-one` or `error`.
+impl FromStr for EofPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<EofPolicy, Self::Err> {
+        match name {
+            "unchanged" => Ok(EofPolicy::Unchanged),
+            "zero" => Ok(EofPolicy::Zero),
+            "minus-one" => Ok(EofPolicy::MinusOne),
+            "error" => Ok(EofPolicy::Error),
+            _ => Err(format!(
+                "unknown end of input policy {}, expected one of: unchanged, zero, minus-one, error",
+                name
+            )),
+        }
+    }
+}
+
+/// Provide enum of rules to map cell values to bytes for `,` and `.` instructions.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum CellEncoding {
+    /// `.` writes the lowest byte of the value, `,` reads one byte. Default one.
+    #[default]
+    Byte,
+    /// `.` writes the value as UTF-8 encoded code point, `,` reads one UTF-8 encoded character.
+    /// Values which are not valid code points are written as U+FFFD.
+    Utf8,
+    /// `.` writes all bytes of the value in little-endian order, `,` reads the same number of bytes.
+    LittleEndian,
+}
+
+/// Parse encoding from its command line name: `byte`, `utf8` or `le`.
+impl FromStr for CellEncoding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<CellEncoding, Self::Err> {
+        match name {
+            "byte" => Ok(CellEncoding::Byte),
+            "utf8" => Ok(CellEncoding::Utf8),
+            "le" => Ok(CellEncoding::LittleEndian),
+            _ => Err(format!(
+                "unknown cell encoding {}, expected one of: byte, utf8, le",
+                name
+            )),
+        }
+    }
+}
+
+/// Provide handle to stop running Virtual Machine from another thread.
+///
+/// Clones share the same state, so cancelling any of them stops every VM the token is given to.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    /// Whether the cancellation is requested.
+    cancelled: Arc<AtomicBool>,
 }
 
-/// Provide implementation for u8 type cell in Virtual Machine.
-impl CellKind for u8 {
-    /// Implementation for u8 cell type of wrapper to increase value by 1 in it.
-    fn wrapping_increment(&mut self) {
-        *self += 1;
+impl CancellationToken {
+    /// Create token which is not cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
     }
-    /// Implementation for u8 cell type of wrapper to decrease value by 1 in it.
-    fn wrapping_decrement(&mut self) {
-        *self -= 1;
+
+    /// Request Virtual Machines using the token to stop with `VMError::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
     }
-    /// Implementation for u8 cell type of wrapper to set value in it.
-    fn wrapping_set_value(&mut self, value: u8) {
-        *self = value;
+
+    /// Check if the cancellation is requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
     }
-    /// Implementation for u8 cell type of wrapper to get value from it.
-    fn wrapping_get_value(&self) -> u8 {
-        *self
+}
+
+/// Provide state of Virtual Machine at the instruction which failed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ErrorContext {
+    /// Line of the failed instruction.
+    pub line: usize,
+    /// Position at the line of the failed instruction.
+    pub position: usize,
+    /// The pointer to the current element of tape.
+    pub head: usize,
+    /// Value of the cell the head points to.
+    pub cell: i128,
+}
+
+/// Provide human-readable format of the failed instruction location and tape state.
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "at line {} column {} (head {}, cell value {})",
+            self.line, self.position, self.head, self.cell
+        )
     }
 }
 
 /// Provide enum of errors for Virtual Machine.
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum VMError {
     /// Represent the case when the lenght of the tape is exceeded.
-    NextElementNotReachable { line: usize, position: usize },
+    NextElementNotReachable(ErrorContext),
     /// Represent the case when element before the first one is trying to be reached.
-    PreviousElementNotReachanble { line: usize, position: usize },
+    PreviousElementNotReachanble(ErrorContext),
     ///IO Error at current instruction
-    IOError { line: usize, position: usize },
+    IOError {
+        context: ErrorContext,
+        source: io::Error,
+    },
+    /// Represent the case when `,` reaches the end of input in `Error` end of input policy.
+    EndOfInput(ErrorContext),
+    /// Represent the case when `+` goes past the maximum cell value in `Trap` overflow policy.
+    CellOverflow(ErrorContext),
+    /// Represent the case when `-` goes past the minimum cell value in `Trap` overflow policy.
+    CellUnderflow(ErrorContext),
+    /// Represent the case when the program executes more BF instructions than the step limit allows.
+    FuelExhausted { steps: u64, context: ErrorContext },
+    /// Represent the case when adjustable tape can't grow to `size` cells the head moves to, because of
+    /// its maximum size or lack of memory.
+    TapeLimitExceeded { size: usize, context: ErrorContext },
+    /// Represent the case when the program is cancelled with `CancellationToken`.
+    Cancelled(ErrorContext),
+    /// Represent the case when the program runs past the deadline.
+    DeadlineExceeded(ErrorContext),
+}
+
+impl VMError {
+    /// Get location of the failed instruction and state of the tape.
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            VMError::NextElementNotReachable(context)
+            | VMError::PreviousElementNotReachanble(context)
+            | VMError::IOError { context, .. }
+            | VMError::EndOfInput(context)
+            | VMError::CellOverflow(context)
+            | VMError::CellUnderflow(context)
+            | VMError::FuelExhausted { context, .. }
+            | VMError::TapeLimitExceeded { context, .. }
+            | VMError::Cancelled(context)
+            | VMError::DeadlineExceeded(context) => context,
+        }
+    }
+
+    /// Get diagnostic pointing to the failed instruction with the state of the tape, or only with
+    /// the state if the location is unknown.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let message = match self {
+            VMError::NextElementNotReachable(_) => {
+                "next element of the tape is not reachable".to_string()
+            }
+            VMError::PreviousElementNotReachanble(_) => {
+                "previous element of the tape is not reachable".to_string()
+            }
+            VMError::IOError { source, .. } => format!("IO error: {}", source),
+            VMError::EndOfInput(_) => "end of input".to_string(),
+            VMError::CellOverflow(_) => "cell overflow".to_string(),
+            VMError::CellUnderflow(_) => "cell underflow".to_string(),
+            VMError::FuelExhausted { steps, .. } => {
+                format!("step limit exhausted after {} steps", steps)
+            }
+            VMError::TapeLimitExceeded { size, .. } => {
+                format!("tape can't grow to {} cells", size)
+            }
+            VMError::Cancelled(_) => "cancelled".to_string(),
+            VMError::DeadlineExceeded(_) => "deadline exceeded".to_string(),
+        };
+        let context = self.context();
+        let state = format!("head {}, cell value {}", context.head, context.cell);
+        let diagnostic = Diagnostic::error(message);
+        // Locations start at line 1, line 0 means the program is loaded without them.
+        if context.line == 0 {
+            diagnostic.with_note(state)
+        } else {
+            diagnostic.with_primary(Label::new(context.line, context.position, 1, state))
+        }
+    }
+
+    /// Render diagnostic with the source line of the failed instruction of the program, with ANSI
+    /// colors if `color`.
+    pub fn render(&self, program: &Program, color: bool) -> String {
+        self.diagnostic()
+            .render(program.filename(), program.source(), color)
+    }
+}
+
+/// Provide human-readable format of the Virtual Machine errors.
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VMError::NextElementNotReachable(context) => {
+                write!(f, "next element of the tape is not reachable {}", context)
+            }
+            VMError::PreviousElementNotReachanble(context) => {
+                write!(
+                    f,
+                    "previous element of the tape is not reachable {}",
+                    context
+                )
+            }
+            VMError::IOError { context, source } => write!(f, "IO error {}: {}", context, source),
+            VMError::EndOfInput(context) => write!(f, "end of input {}", context),
+            VMError::CellOverflow(context) => write!(f, "cell overflow {}", context),
+            VMError::CellUnderflow(context) => write!(f, "cell underflow {}", context),
+            VMError::FuelExhausted { steps, context } => {
+                write!(f, "step limit exhausted after {} steps {}", steps, context)
+            }
+            VMError::TapeLimitExceeded { size, context } => {
+                write!(f, "tape can't grow to {} cells {}", size, context)
+            }
+            VMError::Cancelled(context) => write!(f, "cancelled {}", context),
+            VMError::DeadlineExceeded(context) => write!(f, "deadline exceeded {}", context),
+        }
+    }
+}
+
+impl Error for VMError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VMError::IOError { source, .. } => Some(source),
+            _ => None,
+        }
+    }
 }
 
 /// Provide structure for Virtual Machine
@@ -55,242 +390,2293 @@ pub struct VirtualMachine<'a, T> {
     tape_size: usize,
     /// Whether to allow adjust size of the tape of not.
     adjust_tape: bool,
+    /// The maximum number of cells an adjustable tape can grow to, unlimited if `None`.
+    max_tape_size: Option<usize>,
+    /// The rule to map cell values to bytes for `,` and `.` instructions.
+    encoding: CellEncoding,
+    /// The policy for `+` and `-` going past the bounds of the cell value.
+    overflow_policy: OverflowPolicy,
+    /// The policy for `,` when there is no more input.
+    eof_policy: EofPolicy,
     /// The pointer to the current element of tape.
     head: usize,
-    /// BrainFuck Program.
-    program: &'a BrainFuckProgram,
+    /// The index of the instruction to be executed, independent of the tape head.
+    instruction_pointer: usize,
+    /// The index of the first BF instruction not executed yet of the operation at the instruction
+    /// pointer, if the step limit or an error stopped the program in the middle of the operation.
+    resume_source: Option<usize>,
+    /// The number of operations to execute before the next check of cancellation and deadline.
+    until_interrupt_check: u32,
+    /// The number of BF instructions executed so far.
+    steps: u64,
+    /// The maximum number of BF instructions to execute, unlimited if `None`.
+    max_steps: Option<u64>,
+    /// The token to stop the program from another thread.
+    cancellation: Option<CancellationToken>,
+    /// The time to stop the program at, unlimited if `None`.
+    deadline: Option<Instant>,
+    /// The tracer to write executed operations into, no trace if `None`.
+    tracer: Option<Tracer>,
+    /// The number of executions of every BF instruction of the source program, no profiling if `None`.
+    executions: Option<Vec<u64>>,
+    /// The destination and the format of tape dumps written by `#` instruction.
+    dump: TapeDump,
+    /// The changes of the last executed operations to revert them, no history if `None`.
+    history: Option<History>,
+    /// Bytes of input read by reverted operations, which are read again before the input.
+    replay: VecDeque<u8>,
+    /// BrainFuck Program in intermediate representation.
+    program: &'a Program,
 }
 
 impl<'a, T: CellKind> VirtualMachine<'a, T>
 where
     T: CellKind,
 {
-    /// Create VM based on the size, by default is 30 000. Also, it can be adjusted, by default it doesn't.
+    /// Create VM based on the size, by default is 3 000. Also, it can be adjusted, by default it doesn't.
+    ///
+    /// The tape is allocated up front. An adjustable tape doubles its length every time the head moves
+    /// past its end, up to the limit set by [`VirtualMachine::with_max_tape_size`] or
+    /// [`VirtualMachine::with_max_tape_bytes`].
     pub fn new(
-        program: &'a BrainFuckProgram,
+        program: &'a Program,
         size: Option<NonZeroUsize>,
         adjust_tape: Option<bool>,
     ) -> Self {
+        let tape_size = size.map(NonZeroUsize::get).unwrap_or(3000);
         VirtualMachine {
-            tape: Vec::new(),
-            tape_size: size.map(NonZeroUsize::get).unwrap_or(3000),
+            tape: vec![T::default(); tape_size],
+            tape_size,
             adjust_tape: adjust_tape.unwrap_or(false),
+            max_tape_size: None,
+            encoding: CellEncoding::default(),
+            overflow_policy: OverflowPolicy::default(),
+            eof_policy: EofPolicy::default(),
             head: 0,
+            instruction_pointer: 0,
+            resume_source: None,
+            until_interrupt_check: 0,
+            steps: 0,
+            max_steps: None,
+            cancellation: None,
+            deadline: None,
+            tracer: None,
+            executions: None,
+            dump: TapeDump::default(),
+            history: None,
+            replay: VecDeque::new(),
             program,
         }
     }
 
-    /// Interpreter BF program into human-readable format.
-    pub fn interpreter(&self) {
-        for instruction_position in self.program.instructions() {
-            println!(
-                "[{}:{}",
-                self.program.filename().display(),
-                instruction_position
+    /// Move the tape, the head, the step count and the options of VM to execute another program from its
+    /// first operation, e.g. to run snippets one by one on the same tape.
+    ///
+    /// The history and the profiling counts of the previous program are forgotten.
+    pub fn with_program<'b>(self, program: &'b Program) -> VirtualMachine<'b, T> {
+        VirtualMachine {
+            tape: self.tape,
+            tape_size: self.tape_size,
+            adjust_tape: self.adjust_tape,
+            max_tape_size: self.max_tape_size,
+            encoding: self.encoding,
+            overflow_policy: self.overflow_policy,
+            eof_policy: self.eof_policy,
+            head: self.head,
+            instruction_pointer: 0,
+            resume_source: None,
+            until_interrupt_check: 0,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            cancellation: self.cancellation,
+            deadline: self.deadline,
+            tracer: self.tracer,
+            executions: self.executions.map(|_| Vec::new()),
+            dump: self.dump,
+            history: self.history.map(|mut history| {
+                history.clear();
+                history
+            }),
+            replay: self.replay,
+            program,
+        }
+    }
+
+    /// Limit the number of cells an adjustable tape can grow to. It is unlimited by default.
+    ///
+    /// Moving the head past the limit stops the program with `VMError::TapeLimitExceeded`. The tape
+    /// allocated up front by [`VirtualMachine::new`] isn't shrunk to the limit, so the caller checks
+    /// it against [`VirtualMachine::max_tape_size`].
+    pub fn with_max_tape_size(mut self, max_tape_size: Option<NonZeroUsize>) -> Self {
+        self.max_tape_size = max_tape_size.map(NonZeroUsize::get);
+        self
+    }
+
+    /// Limit the number of bytes the cells of an adjustable tape can occupy. It is unlimited by
+    /// default. The lower limit applies if it is also set by [`VirtualMachine::with_max_tape_size`].
+    /// The tape keeps at least one cell even if it takes more bytes than the limit.
+    pub fn with_max_tape_bytes(mut self, max_tape_bytes: Option<NonZeroUsize>) -> Self {
+        if let Some(max_tape_bytes) = max_tape_bytes {
+            let max_tape_size = (max_tape_bytes.get() / T::BYTES.max(1)).max(1);
+            self.max_tape_size = Some(
+                self.max_tape_size
+                    .map_or(max_tape_size, |limit| limit.min(max_tape_size)),
             );
         }
+        self
     }
 
-    /// Go to the next element in tape. If tape size exceeded, error message is shown.
-    fn next_element(&mut self) -> Result<(), VMError> {
-        if self.head + 1 == self.tape_size {
-            let instruction = &self.program.instructions()[self.head];
-            return Err(VMError::NextElementNotReachable {
-                line: instruction.line(),
-                position: instruction.position(),
-            });
+    /// Set the rule to map cell values to bytes for `,` and `.` instructions. It is `Byte` by default.
+    pub fn with_cell_encoding(mut self, encoding: CellEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Set the policy for `+` and `-` going past the bounds of the cell value. It is `Wrap` by default.
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Set the policy for `,` when there is no more input. It is `Unchanged` by default.
+    pub fn with_eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.eof_policy = eof_policy;
+        self
+    }
+
+    /// Limit the number of BF instructions to execute. It is unlimited by default.
+    ///
+    /// Operations built from many BF instructions count all of them, so the program stops with
+    /// `VMError::FuelExhausted` at the same instruction at every optimization level.
+    pub fn with_max_steps(mut self, max_steps: Option<u64>) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Stop the program with `VMError::Cancelled` once the token is cancelled. It can't be cancelled
+    /// by default.
+    ///
+    /// The token is checked every few thousands of operations, so the program stops shortly after
+    /// the cancellation, unless it is waiting for input.
+    pub fn with_cancellation(mut self, cancellation: Option<CancellationToken>) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Stop the program with `VMError::DeadlineExceeded` once the deadline passes. It is unlimited by
+    /// default. The deadline is checked as often as the cancellation token.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Write every executed operation selected by the filter of the tracer. There is no trace by
+    /// default.
+    pub fn with_tracer(mut self, tracer: Option<Tracer>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    /// Take the tracer back, e.g. to flush the trace after the program stops.
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Set the destination and the format of tape dumps written by `#` instruction of the debug dump
+    /// extension. By default text dumps of 8 cells at each side of the head are written into stderr.
+    pub fn with_tape_dump(mut self, dump: TapeDump) -> Self {
+        self.dump = dump;
+        self
+    }
+
+    /// Record the changes of up to `capacity` last executed operations to revert them by
+    /// [`VirtualMachine::step_back`]. There is no history by default.
+    ///
+    /// Every operation keeps the cells it writes and the input it reads, the oldest operation is
+    /// forgotten once the history is full.
+    pub fn with_history(mut self, capacity: usize) -> Self {
+        self.history = (capacity > 0).then(|| History::new(capacity));
+        self
+    }
+
+    /// Count executions of every BF instruction of the source program for [`VirtualMachine::profile`].
+    /// It is disabled by default.
+    pub fn with_profiling(mut self, profiling: bool) -> Self {
+        self.executions = profiling.then(Vec::new);
+        self
+    }
+
+    /// Get executions of BF instructions and loops counted so far, `None` if profiling is disabled.
+    pub fn profile(&self) -> Option<Profile> {
+        self.executions
+            .as_ref()
+            .map(|executions| Profile::new(self.program, executions))
+    }
+
+    /// Get the number of BF instructions executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Set all cells to zero, move the head and the instruction pointer to the start and reset the step
+    /// count, the history and the profiling counts. The tape keeps its size.
+    pub fn reset(&mut self) {
+        self.tape.fill(T::default());
+        self.head = 0;
+        self.instruction_pointer = 0;
+        self.resume_source = None;
+        self.until_interrupt_check = 0;
+        self.steps = 0;
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        if let Some(executions) = self.executions.as_mut() {
+            executions.clear();
+        }
+    }
+
+    /// Revert the latest operation recorded in the history: the cells it wrote, the head, the
+    /// instruction pointer and the step count. The input it read is read again by the next operations.
+    /// Return `false` if there is no operation to revert.
+    ///
+    /// Output, profiling counts and the trace aren't reverted.
+    pub fn step_back(&mut self) -> bool {
+        let Some(change) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+        for &(index, before) in change.writes.iter().rev() {
+            self.tape[index].wrapping_set_value(T::value_from_bits(before));
+        }
+        self.tape.truncate(change.tape_size);
+        self.tape_size = change.tape_size;
+        self.head = change.head;
+        self.instruction_pointer = change.instruction_pointer;
+        self.resume_source = change.resume_source;
+        self.steps = change.steps;
+        for &byte in change.input.iter().rev() {
+            self.replay.push_front(byte);
+        }
+        true
+    }
+
+    /// Get the number of operations which can be reverted by [`VirtualMachine::step_back`].
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
+    /// Find the latest write of the element of tape at `index` in the history, `None` if it isn't
+    /// written by the operations recorded in it.
+    pub fn last_write(&self, index: usize) -> Option<CellWrite> {
+        let current = self.tape.get(index)?.wrapping_get_value();
+        let (change, before) = self.history.as_ref()?.last_write(index)?;
+        Some(CellWrite {
+            step: change.steps,
+            instruction_pointer: change.instruction_pointer,
+            before: T::value_from_bits(before).into(),
+            // There are no later writes, so the cell keeps the value written.
+            after: current.into(),
+        })
+    }
+
+    /// Capture the tape, the head, the instruction pointer, the step count, the profiling counts and
+    /// the options of VM, e.g. to write them into a file and resume the program later.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program_hash: snapshot::program_hash(self.program),
+            cell: CellWidth::of::<T>(),
+            adjust_tape: self.adjust_tape,
+            max_tape_size: self.max_tape_size,
+            encoding: self.encoding,
+            overflow_policy: self.overflow_policy,
+            eof_policy: self.eof_policy,
+            max_steps: self.max_steps,
+            head: self.head,
+            instruction_pointer: self.instruction_pointer,
+            resume_source: self.resume_source,
+            steps: self.steps,
+            cells: self
+                .tape
+                .iter()
+                .map(|cell| T::value_to_bits(cell.wrapping_get_value()))
+                .collect(),
+            executions: self.executions.clone(),
         }
-        self.head += 1;
-        Ok(())
     }
 
-    /// Go to the previous element in tape. If it is the first element, error message is shown.
-    fn previous_element(&mut self) -> Result<(), VMError> {
-        if self.head == 0 {
-            let instruction = &self.program.instructions()[self.head];
-            return Err(VMError::PreviousElementNotReachanble {
-                line: instruction.line(),
-                position: instruction.position(),
+    /// Restore the state captured by [`VirtualMachine::snapshot`], so the program continues exactly
+    /// where it stopped. The snapshot must be taken for the same program compiled with the same
+    /// optimization level and for the same cell type.
+    ///
+    /// Cancellation, deadline, tracer and tape dump of this VM are kept. Profiling counts are taken
+    /// from the snapshot if it has them, otherwise they start from zero if profiling is enabled.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let program_hash = snapshot::program_hash(self.program);
+        if snapshot.program_hash != program_hash {
+            return Err(SnapshotError::ProgramMismatch {
+                expected: program_hash,
+                found: snapshot.program_hash,
             });
         }
-        self.head -= 1;
+        if snapshot.cell != CellWidth::of::<T>() {
+            return Err(SnapshotError::CellMismatch {
+                expected: CellWidth::of::<T>(),
+                found: snapshot.cell,
+            });
+        }
+        if snapshot.instruction_pointer > self.program.instructions().len()
+            || snapshot.head >= snapshot.cells.len()
+        {
+            return Err(SnapshotError::Corrupted(
+                "the position is out of the program or the tape".to_string(),
+            ));
+        }
+
+        self.tape = snapshot
+            .cells
+            .iter()
+            .map(|&bits| {
+                let mut cell = T::default();
+                cell.wrapping_set_value(T::value_from_bits(bits));
+                cell
+            })
+            .collect();
+        self.tape_size = self.tape.len();
+        self.adjust_tape = snapshot.adjust_tape;
+        self.max_tape_size = snapshot.max_tape_size;
+        self.encoding = snapshot.encoding;
+        self.overflow_policy = snapshot.overflow_policy;
+        self.eof_policy = snapshot.eof_policy;
+        self.max_steps = snapshot.max_steps;
+        self.head = snapshot.head;
+        self.instruction_pointer = snapshot.instruction_pointer;
+        self.resume_source = snapshot.resume_source;
+        self.steps = snapshot.steps;
+        self.until_interrupt_check = 0;
+        self.replay.clear();
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        if snapshot.executions.is_some() || self.executions.is_some() {
+            self.executions = Some(snapshot.executions.clone().unwrap_or_default());
+        }
         Ok(())
     }
 
-    /// Add 1 to the element where head is pointing to.
-    fn wrapped_add(&mut self) -> Result<(), u8> {
-        self.tape[self.head].wrapping_increment();
+    /// Execute BF program reading `,` from `input` and writing `.` into `output`.
+    pub fn run(&mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), VMError> {
+        self.until_interrupt_check = 0;
+        while !self.is_finished() {
+            self.step(input, output)?;
+        }
         Ok(())
     }
 
-    /// Substract 1 to the element where head is pointing to.
-    fn wrapped_sub(&mut self) -> Result<(), u8> {
-        self.tape[self.head].wrapping_decrement();
-        Ok(())
+    /// Check if the instruction pointer is past the last operation of the program.
+    pub fn is_finished(&self) -> bool {
+        self.instruction_pointer >= self.program.instructions().len()
     }
 
-    /// Basic IO read.
-    fn read(&mut self, reader: &mut impl Read) -> Result<(), VMError> {
-        let mut buffer = [0; 1];
-        match reader.read_exact(&mut buffer) {
-            Ok(()) => self.tape[self.head].wrapping_set_value(buffer[0]),
-            Err(_err) => {
-                let instruction = &self.program.instructions()[self.head];
-                return Err(VMError::IOError {
-                    line: instruction.line(),
-                    position: instruction.position(),
-                });
-            }
+    /// Execute the operation pointed by the instruction pointer and move to the next one, reading `,`
+    /// from `input` and writing `.` into `output`. Do nothing if the program is finished.
+    ///
+    /// [`VirtualMachine::run`] executes the program by this method, so executing it step by step
+    /// gives the same result. On error the instruction pointer stays at the failed operation. If the
+    /// step limit stopped the program in the middle of the operation, only its rest is executed next
+    /// time, e.g. after the limit is raised.
+    pub fn step(&mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), VMError> {
+        let program = self.program;
+        let Some(instruction) = program.instructions().get(self.instruction_pointer) else {
+            return Ok(());
+        };
+        if self.until_interrupt_check == 0 {
+            self.check_interrupts(instruction.source().start)?;
+            self.until_interrupt_check = INTERRUPT_CHECK_INTERVAL;
+        }
+        self.until_interrupt_check -= 1;
+        let instruction_pointer = self.instruction_pointer;
+        let traced = self.tracer.is_some().then(|| {
+            (
+                self.steps,
+                self.head,
+                self.tape[self.head].wrapping_get_value().into(),
+            )
+        });
+        if self.history.is_some() || !self.replay.is_empty() {
+            self.execute_recorded(instruction, input, output)?;
+        } else {
+            self.execute_operation(instruction, input, output)?;
         }
+        // Loop idiom operation which falls back to its loop doesn't move the instruction pointer and
+        // executes nothing, the loop is traced instead.
+        let fallback = matches!(
+            instruction.op(),
+            Op::Clear(_) | Op::Scan(_) | Op::MultiplyAdd(..)
+        ) && self.instruction_pointer == instruction_pointer;
+        if let Some((step, head, before)) = traced.filter(|_| !fallback) {
+            self.trace(instruction, step, head, before)?;
+        }
+        self.instruction_pointer += 1;
         Ok(())
     }
 
-    /// Basic IO write.
-    fn output(&self, writer: &mut impl Write) -> Result<(), VMError> {
-        match writer.write_all(&[self.tape[self.head].wrapping_get_value()]) {
-            Ok(()) => match writer.flush() {
-                Ok(()) => Ok(()),
-                Err(_err) => {
-                    let instruction = &self.program.instructions()[self.head];
-                    Err(VMError::IOError {
-                        line: instruction.line(),
-                        position: instruction.position(),
-                    })
+    /// Execute the operation pointed by the instruction pointer without moving to the next one.
+    fn execute_operation(
+        &mut self,
+        instruction: &Instruction,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        let resume_source = self
+            .resume_source
+            .take()
+            .filter(|&resume_source| resume_source > instruction.source().start);
+        match *instruction.op() {
+            Op::JumpIfZero(target) => {
+                self.take_step(instruction.source().start)?;
+                if self.tape[self.head].wrapping_get_value() == T::Value::default() {
+                    self.instruction_pointer = target;
+                }
+            }
+            Op::JumpIfNonZero(target) => {
+                self.take_step(instruction.source().start)?;
+                if self.tape[self.head].wrapping_get_value() != T::Value::default() {
+                    self.instruction_pointer = target;
+                }
+            }
+            Op::Clear(step) => {
+                if self.clear(step, instruction.source()) {
+                    self.skip_loop();
+                }
+            }
+            Op::Scan(step) => {
+                if self.scan(step, instruction.source()) {
+                    self.skip_loop();
+                }
+            }
+            Op::MultiplyAdd(step, ref targets, reach) => {
+                if self.multiply_add(step, targets, reach, instruction.source()) {
+                    self.skip_loop();
                 }
+            }
+            Op::Block(ref block) => match resume_source {
+                Some(resume_source) => self.fallback(block, resume_source, input, output)?,
+                None => self.block(block, instruction, input, output)?,
             },
-            Err(_err) => {
-                let instruction = &self.program.instructions()[self.head];
-                Err(VMError::IOError {
-                    line: instruction.line(),
-                    position: instruction.position(),
-                })
+            Op::DebugDump => {
+                self.take_step(instruction.source().start)?;
+                self.dump_tape(instruction.source().start)?;
             }
+            _ => match resume_source {
+                Some(resume_source) => {
+                    self.execute_from(instruction, resume_source, input, output)?
+                }
+                None => self.execute_limited(instruction, input, output)?,
+            },
         }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::BrainFuckProgram;
-    use crate::NonZeroUsize;
-    use crate::VMError;
-    use crate::VirtualMachine;
+    /// Execute the operation recording the state it changes into the history, reading input replayed
+    /// after reverse execution first.
+    fn execute_recorded(
+        &mut self,
+        instruction: &Instruction,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        if let Some(history) = self.history.as_mut() {
+            history.push(Change {
+                instruction_pointer: self.instruction_pointer,
+                head: self.head,
+                steps: self.steps,
+                resume_source: self.resume_source,
+                tape_size: self.tape_size,
+                writes: Vec::new(),
+                input: Vec::new(),
+            });
+        }
+        let mut reader = ReplayReader {
+            replay: mem::take(&mut self.replay),
+            input,
+            read: Vec::new(),
+        };
+        let result = self.execute_operation(instruction, &mut reader, output);
+        self.replay = reader.replay;
+        if let Some(history) = self.history.as_mut() {
+            history.record_input(&reader.read);
+        }
+        result
+    }
 
-    use std::fs::File;
-    use std::io::Write;
-    use tempdir::TempDir;
+    /// Get the index of the operation to be executed next.
+    pub fn instruction_pointer(&self) -> usize {
+        self.instruction_pointer
+    }
 
-    #[test]
-    fn test_default_new_vm() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        let file_path = tmp_dir.path().join("my-temporary-note.txt");
-        let tmp_file = File::create(&file_path).unwrap();
+    /// Get the pointer to the current element of tape.
+    pub fn head(&self) -> usize {
+        self.head
+    }
 
-        let program = BrainFuckProgram::from_file(&file_path).unwrap();
+    /// Get the program executed by the VM.
+    pub fn program(&self) -> &'a Program {
+        self.program
+    }
 
-        let default_vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
-        assert_eq!(default_vm.tape_size, 3000);
-        assert_eq!(default_vm.tape.len(), 0);
-        assert_eq!(default_vm.head, 0);
-        assert!(!default_vm.adjust_tape);
+    /// Get value of the element of tape at `index`, `None` if it is out of the tape.
+    pub fn cell(&self, index: usize) -> Option<T::Value> {
+        self.tape.get(index).map(T::wrapping_get_value)
+    }
 
-        drop(tmp_file);
-        tmp_dir.close().unwrap();
+    /// Set value of the element of tape at `index`. Return `false` if it is out of the tape.
+    ///
+    /// The history is forgotten, as the program can't be reverted past the change it didn't make.
+    pub fn set_cell(&mut self, index: usize, value: T::Value) -> bool {
+        match self.tape.get_mut(index) {
+            Some(cell) => {
+                cell.wrapping_set_value(value);
+                if let Some(history) = self.history.as_mut() {
+                    history.clear();
+                }
+                true
+            }
+            None => false,
+        }
     }
 
-    #[test]
-    fn test_new_vm() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        let file_path = tmp_dir.path().join("my-temporary-note.txt");
-        let tmp_file = File::create(&file_path).unwrap();
+    /// Record the value of the element of tape at `index` into the history before it is written.
+    fn record_write(&mut self, index: usize) {
+        if let Some(history) = self.history.as_mut() {
+            history.record_write(
+                index,
+                T::value_to_bits(self.tape[index].wrapping_get_value()),
+            );
+        }
+    }
 
-        let program = BrainFuckProgram::from_file(&file_path).unwrap();
+    /// Get the size of the tape.
+    pub fn tape_size(&self) -> usize {
+        self.tape_size
+    }
 
-        let vm: VirtualMachine<u8> =
-            VirtualMachine::new(&program, NonZeroUsize::new(100), Some(true));
-        assert_eq!(vm.tape_size, 100);
-        assert_eq!(vm.tape.len(), 0);
-        assert_eq!(vm.head, 0);
-        assert!(vm.adjust_tape);
+    /// Get the number of cells an adjustable tape can grow to, `None` if it is unlimited.
+    pub fn max_tape_size(&self) -> Option<usize> {
+        self.max_tape_size
+    }
 
-        drop(tmp_file);
-        tmp_dir.close().unwrap();
+    /// Execute straight-line operation addressed relative to the head.
+    fn execute(
+        &mut self,
+        instruction: &Instruction,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        let source = instruction.source().start;
+        match *instruction.op() {
+            Op::Add { offset, delta } => self.add_value(offset, delta, source),
+            Op::Move(delta) => {
+                if delta >= 0 {
+                    self.move_right(delta.unsigned_abs(), source)
+                } else {
+                    self.move_left(delta.unsigned_abs(), source)
+                }
+            }
+            Op::Output { offset } => self.output(offset, source, output),
+            Op::Input { offset } => self.read(offset, source, input),
+            _ => unreachable!("only straight-line operations are executed on their own"),
+        }
     }
 
-    #[test]
-    fn test_failed_to_get_previous_element_vm() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        let file_path = tmp_dir.path().join("my-temporary-note.txt");
-        let mut tmp_file = File::create(&file_path).unwrap();
-        let _ = writeln!(tmp_file, "+[-");
+    /// Write the operation executed from `step` with the head and the current cell `before` it, if the
+    /// filter of the tracer selects it.
+    fn trace(
+        &mut self,
+        instruction: &Instruction,
+        step: u64,
+        head: usize,
+        before: i128,
+    ) -> Result<(), VMError> {
+        let source = instruction.source().start;
+        let location = self.program.location(source);
+        let Some(tracer) = self.tracer.as_mut() else {
+            return Ok(());
+        };
+        if !tracer.accepts(step, location.line) {
+            return Ok(());
+        }
+        let record = TraceRecord {
+            step,
+            line: location.line,
+            position: location.position,
+            instruction: instruction.op().to_string(),
+            head,
+            before,
+            after: self.tape[self.head].wrapping_get_value().into(),
+        };
+        tracer.record(&record).map_err(|err| VMError::IOError {
+            context: self.error_context_at(source, self.head),
+            source: err,
+        })
+    }
 
-        let program = BrainFuckProgram::from_file(&file_path).unwrap();
+    /// Stop with error before BF instruction at index `source` if the program is cancelled or runs past
+    /// the deadline.
+    fn check_interrupts(&self, source: usize) -> Result<(), VMError> {
+        if self
+            .cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+        {
+            return Err(VMError::Cancelled(self.error_context_at(source, self.head)));
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(VMError::DeadlineExceeded(
+                self.error_context_at(source, self.head),
+            ));
+        }
+        Ok(())
+    }
 
-        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, NonZeroUsize::new(1), None);
-        assert_eq!(
-            vm.previous_element(),
-            Err(VMError::PreviousElementNotReachanble {
-                line: 1,
-                position: 1
+    /// Write the tape around the head for `#` instruction at index `source` of the source program.
+    fn dump_tape(&mut self, source: usize) -> Result<(), VMError> {
+        let radius = self.dump.radius();
+        let start = self.head.saturating_sub(radius);
+        let end = self.head.saturating_add(radius).min(self.tape_size - 1);
+        let cells: Vec<i128> = self.tape[start..=end]
+            .iter()
+            .map(|cell| cell.wrapping_get_value().into())
+            .collect();
+        let location = self.program.location(source);
+        self.dump
+            .write((location.line, location.position), self.head, start, &cells)
+            .map_err(|err| VMError::IOError {
+                context: self.error_context_at(source, self.head),
+                source: err,
             })
-        );
-
-        drop(tmp_file);
-        tmp_dir.close().unwrap();
     }
 
-    #[test]
-    fn test_get_previous_element_vm() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        let file_path = tmp_dir.path().join("my-temporary-note.txt");
-        let tmp_file = File::create(&file_path).unwrap();
+    /// Get the number of BF instructions the step limit still allows to execute.
+    fn remaining_steps(&self) -> u64 {
+        self.max_steps
+            .map_or(u64::MAX, |max_steps| max_steps.saturating_sub(self.steps))
+    }
 
-        let program = BrainFuckProgram::from_file(&file_path).unwrap();
+    /// Count one BF instruction at index `source` of the source program, or stop with
+    /// `VMError::FuelExhausted` if the step limit is reached.
+    fn take_step(&mut self, source: usize) -> Result<(), VMError> {
+        if self.remaining_steps() == 0 {
+            return Err(self.fuel_exhausted(source));
+        }
+        self.steps = self.steps.saturating_add(1);
+        self.count_executions(source..source + 1, 1);
+        Ok(())
+    }
 
-        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
-        assert_eq!(vm.next_element(), Ok(()));
-        assert_eq!(vm.previous_element(), Ok(()));
+    /// Count `times` executions of BF instructions at indices `source` of the source program if
+    /// profiling is enabled.
+    fn count_executions(&mut self, source: Range<usize>, times: u64) {
+        if let Some(executions) = self.executions.as_mut() {
+            if executions.len() < source.end {
+                executions.resize(source.end, 0);
+            }
+            for count in &mut executions[source] {
+                *count = count.saturating_add(times);
+            }
+        }
+    }
 
-        drop(tmp_file);
-        tmp_dir.close().unwrap();
+    /// Create error for the step limit reached before BF instruction at index `source`.
+    fn fuel_exhausted(&self, source: usize) -> VMError {
+        VMError::FuelExhausted {
+            steps: self.steps,
+            context: self.error_context_at(source, self.head),
+        }
     }
 
-    #[test]
-    fn test_failed_to_get_next_element_vm() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        let file_path = tmp_dir.path().join("my-temporary-note.txt");
-        let mut tmp_file = File::create(&file_path).unwrap();
-        let _ = writeln!(tmp_file, "+[-");
+    /// Execute straight-line operation counting every BF instruction it is built from. If the step
+    /// limit is reached in the middle of the operation, only the allowed part of it is executed.
+    fn execute_limited(
+        &mut self,
+        instruction: &Instruction,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        let source = instruction.source();
+        let cost = source.len() as u64;
+        let allowed = cost.min(self.remaining_steps());
+        if allowed == cost {
+            self.steps = self.steps.saturating_add(cost);
+            self.count_executions(source, 1);
+            return self.execute(instruction, input, output);
+        }
 
-        let program = BrainFuckProgram::from_file(&file_path).unwrap();
+        let partial = match *instruction.op() {
+            Op::Add { offset, delta } => Some(Op::Add {
+                offset,
+                delta: delta.signum() * allowed as i64,
+            }),
+            Op::Move(delta) => Some(Op::Move(delta.signum() * allowed as isize)),
+            _ => None,
+        };
+        let end = source.start + allowed as usize;
+        if let Some(op) = partial.filter(|_| allowed > 0) {
+            self.execute(&Instruction::new(op, source.start..end), input, output)?;
+        }
+        self.steps = self.steps.saturating_add(allowed);
+        self.count_executions(source.start..end, 1);
+        self.resume_source = Some(end);
+        Err(self.fuel_exhausted(end))
+    }
 
-        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, NonZeroUsize::new(3), None);
-        let _ = vm.next_element();
-        let _ = vm.next_element();
-        assert_eq!(
-            vm.next_element(),
-            Err(VMError::NextElementNotReachable {
-                line: 1,
-                position: 3,
-            })
-        );
+    /// Execute the rest of straight-line operation starting at BF instruction at index `resume_source`
+    /// of the source program, when the step limit stopped the operation in the middle.
+    fn execute_from(
+        &mut self,
+        instruction: &Instruction,
+        resume_source: usize,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        let source = instruction.source();
+        if source.end <= resume_source {
+            return Ok(());
+        }
+        if source.start >= resume_source {
+            return self.execute_limited(instruction, input, output);
+        }
+        let rest = source.end - resume_source;
+        let op = match *instruction.op() {
+            Op::Add { offset, delta } => Op::Add {
+                offset,
+                delta: delta.signum() * rest as i64,
+            },
+            Op::Move(delta) => Op::Move(delta.signum() * rest as isize),
+            ref op => op.clone(),
+        };
+        self.execute_limited(
+            &Instruction::new(op, resume_source..source.end),
+            input,
+            output,
+        )
+    }
 
-        drop(tmp_file);
-        tmp_dir.close().unwrap();
+    /// Execute block of operations addressed relative to the head and move the head once at its
+    /// end. If the block passes cells out of the tape, the operations it is built from are executed
+    /// instead, so the tape grows or the error points to the instruction which failed to move.
+    ///
+    /// The operations it is built from are also executed when the step limit doesn't allow the whole
+    /// block, so it stops at the right instruction. If an operation of the block fails, the head and
+    /// the step count are left as the operations it is built from leave them, and the rest of the
+    /// block is executed from the failed instruction next time, e.g. after the program is resumed.
+    fn block(
+        &mut self,
+        block: &Block,
+        instruction: &Instruction,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        let (lowest, highest) = block.reach();
+        let fits_tape = self.head.checked_add_signed(lowest).is_some()
+            && matches!(self.head.checked_add_signed(highest), Some(index) if index < self.tape_size);
+        let cost = instruction.source().len() as u64;
+        if !fits_tape || cost > self.remaining_steps() {
+            return self.fallback(block, instruction.source().start, input, output);
+        }
+        let (head, steps) = (self.head, self.steps);
+        self.steps = self.steps.saturating_add(cost);
+        self.count_executions(instruction.source(), 1);
+        for operation in block.instructions() {
+            if let Err(err) = self.execute(operation, input, output) {
+                let offset = match *operation.op() {
+                    Op::Add { offset, .. } | Op::Output { offset } | Op::Input { offset } => offset,
+                    _ => 0,
+                };
+                let executed = operation.source().end - instruction.source().start;
+                self.head = head.wrapping_add_signed(offset);
+                self.steps = steps.saturating_add(executed as u64);
+                self.resume_source = Some(operation.source().start);
+                return Err(err);
+            }
+        }
+        self.head = self.head.wrapping_add_signed(block.shift());
+        Ok(())
     }
 
-    #[test]
-    fn test_next_element_vm() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        let file_path = tmp_dir.path().join("my-temporary-note.txt");
-        let tmp_file = File::create(&file_path).unwrap();
+    /// Execute operations the block is built from, starting at BF instruction at index
+    /// `resume_source`. If one of them fails, the rest is executed from it next time.
+    fn fallback(
+        &mut self,
+        block: &Block,
+        resume_source: usize,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        for instruction in block.fallback() {
+            if let Err(err) = self.execute_from(instruction, resume_source, input, output) {
+                // The step limit already keeps the progress inside the operation.
+                self.resume_source
+                    .get_or_insert(instruction.source().start.max(resume_source));
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
 
-        let program = BrainFuckProgram::from_file(&file_path).unwrap();
+    /// Skip the loop following the loop idiom operation pointed by instruction pointer.
+    fn skip_loop(&mut self) {
+        self.instruction_pointer += 1;
+        if let Op::JumpIfZero(target) = *self.program.instructions()[self.instruction_pointer].op()
+        {
+            self.instruction_pointer = target;
+        }
+    }
 
-        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
-        assert_eq!(vm.next_element(), Ok(()));
+    /// Count BF instructions executed by the loop of BF instructions at indices `source` in
+    /// `iterations`. Return `false` without counting if the step limit doesn't allow the whole loop.
+    fn take_loop_steps(&mut self, source: Range<usize>, iterations: u64) -> bool {
+        // The loop checks the cell once on entry, then runs its body and `]` every iteration.
+        let cost = iterations
+            .saturating_mul(source.len() as u64 - 1)
+            .saturating_add(1);
+        if cost > self.remaining_steps() {
+            return false;
+        }
+        self.steps = self.steps.saturating_add(cost);
+        self.count_executions(source.start..source.start + 1, 1);
+        self.count_executions(source.start + 1..source.end, iterations);
+        true
+    }
 
-        drop(tmp_file);
-        tmp_dir.close().unwrap();
+    /// Get the number of iterations the loop adding `step` to the current cell runs until the cell is
+    /// zero, when the value wraps around at its bounds.
+    fn loop_iterations(&self, step: i64) -> u64 {
+        let mask = u64::MAX >> (64 - 8 * T::BYTES);
+        let bits = T::value_to_bits(self.tape[self.head].wrapping_get_value()) & mask;
+        if step < 0 {
+            bits
+        } else {
+            bits.wrapping_neg() & mask
+        }
+    }
+
+    /// Set the current cell to zero as `[-]` or `[+]` at indices `source` does, where `step` is the
+    /// value the loop adds. Return `false` without changes if the loop would go past the bound of the
+    /// cell value or the step limit.
+    fn clear(&mut self, step: i64, source: Range<usize>) -> bool {
+        let value: i128 = self.tape[self.head].wrapping_get_value().into();
+        let reaches_zero =
+            self.overflow_policy == OverflowPolicy::Wrap || value == 0 || (value > 0) == (step < 0);
+        if !reaches_zero || !self.take_loop_steps(source, self.loop_iterations(step)) {
+            return false;
+        }
+        self.record_write(self.head);
+        self.tape[self.head].wrapping_set_value(T::Value::default());
+        true
+    }
+
+    /// Move the head by `step` cells until it points to zero cell as `[>]` or `[<]` at indices `source`
+    /// does. Return `false` without changes if zero cell isn't found before the bound of the tape or
+    /// the step limit.
+    fn scan(&mut self, step: isize, source: Range<usize>) -> bool {
+        let mut index = self.head;
+        let mut iterations = 0;
+        while self.tape[index].wrapping_get_value() != T::Value::default() {
+            match index.checked_add_signed(step) {
+                Some(next) if next < self.tape_size => index = next,
+                _ => return false,
+            }
+            iterations += 1;
+        }
+        if !self.take_loop_steps(source, iterations) {
+            return false;
+        }
+        self.head = index;
+        true
+    }
+
+    /// Add the current cell multiplied by factor to the cell at offset for every pair and set the
+    /// current cell to zero, as the loop at indices `source` adding `step` to the current cell and
+    /// visiting cells at offsets `reach` does. Return `false` without changes if the loop would go
+    /// past the bound of the cell value, of the tape or the step limit.
+    fn multiply_add(
+        &mut self,
+        step: i64,
+        targets: &[(isize, i64)],
+        (lowest, highest): (isize, isize),
+        source: Range<usize>,
+    ) -> bool {
+        let value = self.tape[self.head].wrapping_get_value();
+        if value == T::Value::default() {
+            return self.take_loop_steps(source, 0);
+        }
+        if self.overflow_policy != OverflowPolicy::Wrap {
+            return false;
+        }
+        let fits_tape = self.head.checked_add_signed(lowest).is_some()
+            && matches!(self.head.checked_add_signed(highest), Some(index) if index < self.tape_size);
+        let iterations = self.loop_iterations(step);
+        if !fits_tape || !self.take_loop_steps(source, iterations) {
+            return false;
+        }
+
+        for &(offset, factor) in targets {
+            let index = self.head.wrapping_add_signed(offset);
+            self.record_write(index);
+            self.tape[index].wrapping_add_value(iterations.wrapping_mul(factor as u64) as i64);
+        }
+        self.record_write(self.head);
+        self.tape[self.head].wrapping_set_value(T::Value::default());
+        true
+    }
+
+    /// Interpreter BF program into human-readable format.
+    pub fn interpreter(&self) {
+        for instruction in self.program.instructions() {
+            let location = self.program.location(instruction.source().start);
+            println!(
+                "[{}:{}:{}] {}",
+                self.program.filename().display(),
+                location.line,
+                location.position,
+                instruction.op()
+            );
+        }
+    }
+
+    /// Get location of BF instruction at index `source` of the source program and state of the tape
+    /// at `head`.
+    fn error_context_at(&self, source: usize, head: usize) -> ErrorContext {
+        let location = self.program.location(source);
+        ErrorContext {
+            line: location.line,
+            position: location.position,
+            head,
+            cell: self.tape[head].wrapping_get_value().into(),
+        }
+    }
+
+    /// Go `count` elements right in tape by the operation built from BF instructions starting at
+    /// `source`. If tape size exceeded and it can't grow, the head stops at the last element and error
+    /// message is shown for the instruction which failed to move.
+    fn move_right(&mut self, count: usize, source: usize) -> Result<(), VMError> {
+        let target = self.head.saturating_add(count);
+        while target >= self.tape_size && self.grow_tape() {}
+        if target >= self.tape_size {
+            let steps = self.tape_size - 1 - self.head;
+            self.head = self.tape_size - 1;
+            let context = self.error_context_at(source + steps, self.head);
+            return Err(if self.adjust_tape {
+                VMError::TapeLimitExceeded {
+                    size: self.tape_size + 1,
+                    context,
+                }
+            } else {
+                VMError::NextElementNotReachable(context)
+            });
+        }
+        self.head = target;
+        Ok(())
+    }
+
+    /// Double the length of adjustable tape without exceeding its maximum size. Return whether it grew,
+    /// it doesn't if there is not enough memory.
+    fn grow_tape(&mut self) -> bool {
+        if !self.adjust_tape {
+            return false;
+        }
+        let limit = self.max_tape_size.unwrap_or(usize::MAX);
+        let new_size = self.tape_size.saturating_mul(2).min(limit);
+        if new_size <= self.tape_size
+            || self
+                .tape
+                .try_reserve_exact(new_size - self.tape.len())
+                .is_err()
+        {
+            return false;
+        }
+        self.tape.resize(new_size, T::default());
+        self.tape_size = new_size;
+        true
+    }
+
+    /// Go `count` elements left in tape by the operation built from BF instructions starting at
+    /// `source`. If the first element is passed, the head stops at it and error message is shown for
+    /// the instruction which failed to move.
+    fn move_left(&mut self, count: usize, source: usize) -> Result<(), VMError> {
+        if count > self.head {
+            let steps = self.head;
+            self.head = 0;
+            return Err(VMError::PreviousElementNotReachanble(
+                self.error_context_at(source + steps, self.head),
+            ));
+        }
+        self.head -= count;
+        Ok(())
+    }
+
+    /// Add `delta` to the element at `offset` from the head following the overflow policy.
+    fn add_value(&mut self, offset: isize, delta: i64, source: usize) -> Result<(), VMError> {
+        let index = self.head.wrapping_add_signed(offset);
+        self.record_write(index);
+        let cell = &mut self.tape[index];
+        match self.overflow_policy {
+            OverflowPolicy::Wrap => cell.wrapping_add_value(delta),
+            OverflowPolicy::Saturate => cell.saturating_add_value(delta),
+            OverflowPolicy::Trap => {
+                if let Err(steps) = cell.checked_add_value(delta) {
+                    let context = self.error_context_at(source + steps as usize, index);
+                    return Err(if delta > 0 {
+                        VMError::CellOverflow(context)
+                    } else {
+                        VMError::CellUnderflow(context)
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Basic IO read of one value in the encoding of the VM into the element at `offset` from the head.
+    fn read(
+        &mut self,
+        offset: isize,
+        source: usize,
+        reader: &mut impl Read,
+    ) -> Result<(), VMError> {
+        let index = self.head.wrapping_add_signed(offset);
+        let bits = match self.encoding {
+            CellEncoding::Byte => {
+                let mut buffer = [0; 1];
+                reader
+                    .read_exact(&mut buffer)
+                    .map(|()| u64::from(buffer[0]))
+            }
+            CellEncoding::Utf8 => read_utf8_char(reader).map(u64::from),
+            CellEncoding::LittleEndian => {
+                let mut buffer = [0; 8];
+                reader
+                    .read_exact(&mut buffer[..T::BYTES])
+                    .map(|()| u64::from_le_bytes(buffer))
+            }
+        };
+        let bits = match bits {
+            Ok(bits) => bits,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => match self.eof_policy {
+                EofPolicy::Unchanged => return Ok(()),
+                EofPolicy::Zero => 0,
+                EofPolicy::MinusOne => u64::MAX,
+                EofPolicy::Error => {
+                    return Err(VMError::EndOfInput(self.error_context_at(source, index)))
+                }
+            },
+            Err(err) => {
+                return Err(VMError::IOError {
+                    context: self.error_context_at(source, index),
+                    source: err,
+                })
+            }
+        };
+        self.record_write(index);
+        self.tape[index].wrapping_set_value(T::value_from_bits(bits));
+        Ok(())
+    }
+
+    /// Basic IO write of one value in the encoding of the VM from the element at `offset` from the head.
+    fn output(&self, offset: isize, source: usize, writer: &mut impl Write) -> Result<(), VMError> {
+        let index = self.head.wrapping_add_signed(offset);
+        let bits = T::value_to_bits(self.tape[index].wrapping_get_value());
+        let mut utf8_buffer = [0; 4];
+        let le_bytes = bits.to_le_bytes();
+        let bytes: &[u8] = match self.encoding {
+            CellEncoding::Byte => &le_bytes[..1],
+            CellEncoding::Utf8 => u32::try_from(bits)
+                .ok()
+                .and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER)
+                .encode_utf8(&mut utf8_buffer)
+                .as_bytes(),
+            CellEncoding::LittleEndian => &le_bytes[..T::BYTES],
+        };
+        writer
+            .write_all(bytes)
+            .and_then(|()| writer.flush())
+            .map_err(|err| VMError::IOError {
+                context: self.error_context_at(source, index),
+                source: err,
+            })
+    }
+}
+
+/// Provide reader of input replayed after reverse execution and then of the input, which keeps the
+/// bytes it reads.
+struct ReplayReader<'r, R> {
+    /// Bytes read by reverted operations.
+    replay: VecDeque<u8>,
+    /// The input read when the replayed bytes are over.
+    input: &'r mut R,
+    /// Bytes read so far.
+    read: Vec<u8>,
+}
+
+impl<R: Read> Read for ReplayReader<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let count = if self.replay.is_empty() {
+            self.input.read(buffer)?
+        } else {
+            self.replay.read(buffer)?
+        };
+        self.read.extend_from_slice(&buffer[..count]);
+        Ok(count)
+    }
+}
+
+/// Read one UTF-8 encoded character. Invalid sequence is read as U+FFFD.
+fn read_utf8_char(reader: &mut impl Read) -> io::Result<char> {
+    let mut buffer = [0; 4];
+    reader.read_exact(&mut buffer[..1])?;
+    let length = match buffer[0] {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(char::REPLACEMENT_CHARACTER),
+    };
+    reader.read_exact(&mut buffer[1..length])?;
+    Ok(std::str::from_utf8(&buffer[..length])
+        .ok()
+        .and_then(|symbol| symbol.chars().next())
+        .unwrap_or(char::REPLACEMENT_CHARACTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{self, CellWidth};
+    use crate::dump::{DumpFormat, TapeDump};
+    use crate::history::CellWrite;
+    use crate::ir::{OptimizationLevel, Program};
+    use crate::snapshot::Snapshot;
+    use crate::trace::{TraceFilter, Tracer};
+    use crate::CancellationToken;
+    use crate::CellEncoding;
+    use crate::CellKind;
+    use crate::EofPolicy;
+    use crate::ErrorContext;
+    use crate::NonZeroUsize;
+    use crate::OverflowPolicy;
+    use crate::VMError;
+    use crate::VirtualMachine;
+    use btf_types::{BrainFuckProgram, Extensions};
+
+    use std::fs::{self, File};
+    use std::io::{self, Read};
+    use std::num::NonZeroU64;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_default_new_vm() {
+        let program = BrainFuckProgram::from_str("")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let default_vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        assert_eq!(default_vm.tape_size, 3000);
+        assert_eq!(default_vm.tape.len(), 3000);
+        assert_eq!(default_vm.head, 0);
+        assert!(!default_vm.adjust_tape);
+    }
+
+    #[test]
+    fn test_new_vm() {
+        let program = BrainFuckProgram::from_str("")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, NonZeroUsize::new(100), Some(true));
+        assert_eq!(vm.tape_size, 100);
+        assert_eq!(vm.tape.len(), 100);
+        assert_eq!(vm.head, 0);
+        assert!(vm.adjust_tape);
+    }
+
+    #[test]
+    fn test_failed_to_get_previous_element_vm() {
+        let program = BrainFuckProgram::from_str("+[-]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, NonZeroUsize::new(1), None);
+        assert!(matches!(
+            vm.move_left(1, 0),
+            Err(VMError::PreviousElementNotReachanble(ErrorContext {
+                line: 1,
+                position: 1,
+                head: 0,
+                cell: 0,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_get_previous_element_vm() {
+        let program = BrainFuckProgram::from_str("")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        assert!(matches!(vm.move_right(1, 0), Ok(())));
+        assert!(matches!(vm.move_left(1, 0), Ok(())));
+    }
+
+    #[test]
+    fn test_failed_to_get_next_element_vm() {
+        let program = BrainFuckProgram::from_str("+[-]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, NonZeroUsize::new(3), None);
+        let _ = vm.move_right(1, 0);
+        let _ = vm.move_right(1, 0);
+        assert!(matches!(
+            vm.move_right(1, 0),
+            Err(VMError::NextElementNotReachable(ErrorContext {
+                line: 1,
+                position: 1,
+                head: 2,
+                cell: 0,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_next_element_vm() {
+        let program = BrainFuckProgram::from_str("")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        assert!(matches!(vm.move_right(1, 0), Ok(())));
+    }
+
+    #[test]
+    fn test_run_vm() {
+        let program = BrainFuckProgram::from_str(
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+        )
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn test_run_vm_echo_input() {
+        let program = BrainFuckProgram::from_str(",.>,.<.")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        let mut input: &[u8] = b"ab";
+        let mut output = Vec::new();
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(output, b"aba");
+    }
+
+    #[test]
+    fn test_grow_extensible_tape_vm() {
+        let program = BrainFuckProgram::from_str(">>>>+")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, NonZeroUsize::new(2), Some(true));
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(vm.tape_size, 8);
+        assert_eq!(vm.tape.len(), 8);
+        assert_eq!(vm.head, 4);
+        assert_eq!(vm.tape[4], 1);
+    }
+
+    #[test]
+    fn test_failed_to_grow_extensible_tape_above_max_size_vm() {
+        let program = BrainFuckProgram::from_str(">>>>+")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, NonZeroUsize::new(2), Some(true))
+                .with_max_tape_size(NonZeroUsize::new(3));
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::TapeLimitExceeded {
+                size: 4,
+                context: ErrorContext {
+                    line: 1,
+                    position: 3,
+                    head: 2,
+                    cell: 0,
+                },
+            })
+        ));
+        assert_eq!(vm.tape.len(), 3);
+
+        let mut vm: VirtualMachine<u16> =
+            VirtualMachine::new(&program, NonZeroUsize::new(2), Some(true))
+                .with_max_tape_size(NonZeroUsize::new(8))
+                .with_max_tape_bytes(NonZeroUsize::new(7));
+        assert_eq!(vm.max_tape_size(), Some(3));
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::TapeLimitExceeded { size: 4, .. })
+        ));
+        assert_eq!(vm.tape.len(), 3);
+
+        // The byte limit smaller than the cell still allows one cell.
+        let vm: VirtualMachine<u32> = VirtualMachine::new(&program, NonZeroUsize::new(1), None)
+            .with_max_tape_bytes(NonZeroUsize::new(3));
+        assert_eq!(vm.max_tape_size(), Some(1));
+    }
+
+    #[test]
+    fn test_error_points_to_failed_instruction_vm() {
+        let program = BrainFuckProgram::from_str("+++\n>>>>>>+<+")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, NonZeroUsize::new(4), None);
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::NextElementNotReachable(ErrorContext {
+                line: 2,
+                position: 4,
+                head: 3,
+                cell: 0,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_wide_cells_vm() {
+        let program = BrainFuckProgram::from_str("-.>,-.")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut input: &[u8] = &[0x34, 0x12];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u16> = VirtualMachine::new(&program, None, None)
+            .with_cell_encoding(CellEncoding::LittleEndian);
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(vm.tape[0], u16::MAX);
+        assert_eq!(vm.tape[1], 0x1233);
+        assert_eq!(output, vec![0xff, 0xff, 0x33, 0x12]);
+
+        let mut input: &[u8] = &[0x00];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<i8> = VirtualMachine::new(&program, None, None);
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(vm.tape[0], -1);
+        assert_eq!(vm.tape[1], -1);
+        assert_eq!(output, vec![0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_utf8_encoding_vm() {
+        let program = BrainFuckProgram::from_str(",+.>-.")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut input: &[u8] = "λ".as_bytes();
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u32> =
+            VirtualMachine::new(&program, None, None).with_cell_encoding(CellEncoding::Utf8);
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(vm.tape[0], 0x3bc);
+        assert_eq!(String::from_utf8(output).unwrap(), "μ\u{fffd}");
+    }
+
+    #[test]
+    fn test_overflow_policy_vm() {
+        let program = BrainFuckProgram::from_str("+>\n-")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        vm.tape[0] = u8::MAX;
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(vm.tape[..2], [0, u8::MAX]);
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None)
+            .with_overflow_policy(OverflowPolicy::Saturate);
+        vm.tape[0] = u8::MAX;
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(vm.tape[..2], [u8::MAX, 0]);
+
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_overflow_policy(OverflowPolicy::Trap);
+        vm.tape[0] = u8::MAX;
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::CellOverflow(ErrorContext {
+                line: 1,
+                position: 1,
+                head: 0,
+                cell: 255,
+            }))
+        ));
+
+        let mut vm: VirtualMachine<i8> =
+            VirtualMachine::new(&program, None, None).with_overflow_policy(OverflowPolicy::Trap);
+        vm.tape[1] = i8::MIN;
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::CellUnderflow(ErrorContext {
+                line: 2,
+                position: 1,
+                head: 1,
+                cell: -128,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_eof_policy_vm() {
+        let program = BrainFuckProgram::from_str("+,")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut output = Vec::new();
+        let expected_cells = [
+            (EofPolicy::Unchanged, 1),
+            (EofPolicy::Zero, 0),
+            (EofPolicy::MinusOne, -1),
+        ];
+        for (eof_policy, expected_cell) in expected_cells {
+            let mut input: &[u8] = &[];
+            let mut vm: VirtualMachine<i8> =
+                VirtualMachine::new(&program, None, None).with_eof_policy(eof_policy);
+            assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+            assert_eq!(vm.tape[0], expected_cell, "EOF policy {:?}", eof_policy);
+        }
+
+        let mut input: &[u8] = &[];
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_eof_policy(EofPolicy::Error);
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::EndOfInput(ErrorContext {
+                line: 1,
+                position: 2,
+                head: 0,
+                cell: 1,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_io_error_vm() {
+        struct BrokenReader;
+
+        impl Read for BrokenReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken reader"))
+            }
+        }
+
+        let program = BrainFuckProgram::from_str(",")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_eof_policy(EofPolicy::Error);
+        match vm.run(&mut BrokenReader, &mut output) {
+            Err(VMError::IOError { context, source }) => {
+                assert_eq!(context.position, 1);
+                assert_eq!(source.kind(), io::ErrorKind::BrokenPipe);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_coalesced_operations_errors_vm() {
+        let program = BrainFuckProgram::from_str("+++>\n<<<")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Coalesce);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_overflow_policy(OverflowPolicy::Trap);
+        vm.tape[0] = 254;
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::CellOverflow(ErrorContext {
+                line: 1,
+                position: 2,
+                head: 0,
+                cell: 255,
+            }))
+        ));
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::PreviousElementNotReachanble(ErrorContext {
+                line: 2,
+                position: 2,
+                head: 0,
+                cell: 3,
+            }))
+        ));
+    }
+
+    /// Run the program with the optimization level and the step limit and return the tape, the output
+    /// and the result.
+    fn run_at_level<T: CellKind>(
+        content: &str,
+        level: OptimizationLevel,
+        policy: OverflowPolicy,
+        max_steps: Option<u64>,
+    ) -> (Vec<T>, Vec<u8>, String) {
+        let program = BrainFuckProgram::from_str(content)
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, level);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<T> =
+            VirtualMachine::new(&program, NonZeroUsize::new(16), Some(false))
+                .with_overflow_policy(policy)
+                .with_max_steps(max_steps);
+        let result = format!("{:?}", vm.run(&mut input, &mut output));
+
+        (vm.tape, output, result)
+    }
+
+    #[test]
+    fn test_loop_idioms_match_naive_vm() {
+        let programs = [
+            "+++++[-]>+",
+            "---[+]>+",
+            "+>+>+<<[>]+",
+            "+>+>+>>+<<<<[>>]+",
+            ">>+>+>+[<]",
+            "+>+>+[<]+",
+            "+++++++[>>>>>>>>>>>>[>]+<<<<<<<<<<<<<<-]",
+            "++++++[->++>+++<<]>.>.",
+            "++++++[-<++>]",
+            "++++++[->>>>>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<<<<]",
+            "++++++[>+<-]+[->>><+<<]",
+            "--[+>+>--<<]>.>.",
+            "+[->>>>>>>>>>>>>>>>+-<<<<<<<<<<<<<<<<]",
+        ];
+        let policies = [
+            OverflowPolicy::Wrap,
+            OverflowPolicy::Saturate,
+            OverflowPolicy::Trap,
+        ];
+        for content in programs {
+            for policy in policies {
+                assert_eq!(
+                    run_at_level::<u8>(content, OptimizationLevel::None, policy, None),
+                    run_at_level::<u8>(content, OptimizationLevel::Idioms, policy, None),
+                    "Program {} with u8 cells and {:?} policy",
+                    content,
+                    policy
+                );
+                assert_eq!(
+                    run_at_level::<i32>(content, OptimizationLevel::None, policy, None),
+                    run_at_level::<i32>(content, OptimizationLevel::Idioms, policy, None),
+                    "Program {} with i32 cells and {:?} policy",
+                    content,
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_offset_blocks_match_naive_vm() {
+        let programs = [
+            "+>++>+++<<.>.>.",
+            "+++[>++>+++<<-]>>.<.",
+            ">>+<<->+<->>>>-<<.",
+            "+>>>>>>>>>>>>>>>>+",
+            "+>+>>>>>>>>>>+<<<<<<<<<<<<<<+",
+            "+[>+]",
+            "->+<-",
+            ",>,<.",
+        ];
+        let policies = [
+            OverflowPolicy::Wrap,
+            OverflowPolicy::Saturate,
+            OverflowPolicy::Trap,
+        ];
+        for content in programs {
+            for policy in policies {
+                assert_eq!(
+                    run_at_level::<u8>(content, OptimizationLevel::None, policy, None),
+                    run_at_level::<u8>(content, OptimizationLevel::Offsets, policy, None),
+                    "Program {} with u8 cells and {:?} policy",
+                    content,
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_limit_vm() {
+        let program = BrainFuckProgram::from_str("+\n[]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Offsets);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_max_steps(Some(100));
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::FuelExhausted {
+                steps: 100,
+                context: ErrorContext {
+                    line: 2,
+                    position: 2,
+                    head: 0,
+                    cell: 1,
+                },
+            })
+        ));
+        assert_eq!(vm.steps(), 100);
+    }
+
+    #[test]
+    fn test_step_limit_match_naive_vm() {
+        let programs = [
+            "+++++[-]>+",
+            "+>+>+<<[>]+",
+            "++++++[->++>+++<<]>.>.",
+            "+++[>++>+++<<-]>>.<.",
+            "+>>>>>>>>>>>>>>>>+",
+        ];
+        for content in programs {
+            for max_steps in 0..120 {
+                let naive = run_at_level::<u8>(
+                    content,
+                    OptimizationLevel::None,
+                    OverflowPolicy::Wrap,
+                    Some(max_steps),
+                );
+                for level in [OptimizationLevel::Idioms, OptimizationLevel::Offsets] {
+                    assert_eq!(
+                        naive,
+                        run_at_level::<u8>(content, level, OverflowPolicy::Wrap, Some(max_steps)),
+                        "Program {} at {:?} with {} steps",
+                        content,
+                        level,
+                        max_steps
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_cancellation_vm() {
+        let program = BrainFuckProgram::from_str("+\n[]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Offsets);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_cancellation(Some(token));
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::Cancelled(ErrorContext {
+                line: 2,
+                head: 0,
+                cell: 1,
+                ..
+            }))
+        ));
+        handle.join().unwrap();
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None)
+            .with_deadline(Some(Instant::now() + Duration::from_millis(20)));
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::DeadlineExceeded(ErrorContext { line: 2, .. }))
+        ));
+
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_deadline(Some(Instant::now()));
+        assert!(matches!(
+            vm.run(&mut input, &mut output),
+            Err(VMError::DeadlineExceeded(ErrorContext {
+                line: 1,
+                position: 1,
+                head: 0,
+                cell: 0,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_trace_vm() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let trace_path = tmp_dir.path().join("trace.jsonl");
+
+        let program = BrainFuckProgram::from_str("++\n[->+<]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::None);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let tracer = Tracer::new(
+            File::create(&trace_path).unwrap(),
+            TraceFilter {
+                lines: Some(2..=2),
+                steps: Some(2..=9),
+                every: NonZeroU64::new(3).unwrap(),
+            },
+        );
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_tracer(Some(tracer));
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        vm.take_tracer().unwrap().flush().unwrap();
+
+        let trace = fs::read_to_string(&trace_path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"step":2,"line":2,"position":1,"instruction":"Jump to 7 if zero","head":0,"before":2,"after":2}"#,
+                r#"{"step":5,"line":2,"position":4,"instruction":"Add 1","head":1,"before":0,"after":1}"#,
+                r#"{"step":8,"line":2,"position":2,"instruction":"Add -1","head":0,"before":1,"after":0}"#,
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_trace_idiom_fallback() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let trace_path = tmp_dir.path().join("trace.jsonl");
+
+        let program = BrainFuckProgram::from_str("+[-]+[->+<]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Idioms);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let tracer = Tracer::new(File::create(&trace_path).unwrap(), TraceFilter::default());
+        // Multiplication falls back to the loop as the cell values saturate.
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None)
+            .with_overflow_policy(OverflowPolicy::Saturate)
+            .with_tracer(Some(tracer));
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        vm.take_tracer().unwrap().flush().unwrap();
+
+        let trace = fs::read_to_string(&trace_path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"step":0,"line":1,"position":1,"instruction":"Add 1","head":0,"before":0,"after":1}"#,
+                r#"{"step":1,"line":1,"position":2,"instruction":"Clear","head":0,"before":1,"after":0}"#,
+                r#"{"step":4,"line":1,"position":5,"instruction":"Add 1","head":0,"before":0,"after":1}"#,
+                r#"{"step":5,"line":1,"position":6,"instruction":"Jump to 12 if zero","head":0,"before":1,"after":1}"#,
+                r#"{"step":6,"line":1,"position":7,"instruction":"Add -1","head":0,"before":1,"after":0}"#,
+                r#"{"step":7,"line":1,"position":8,"instruction":"Move 1","head":0,"before":0,"after":0}"#,
+                r#"{"step":8,"line":1,"position":9,"instruction":"Add 1","head":1,"before":0,"after":1}"#,
+                r#"{"step":9,"line":1,"position":10,"instruction":"Move -1","head":1,"before":1,"after":0}"#,
+                r#"{"step":10,"line":1,"position":11,"instruction":"Jump to 7 if non zero","head":0,"before":0,"after":0}"#,
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_profile_vm() {
+        let program = BrainFuckProgram::from_str("+++[>++[>+<-]<-]\n>>[-]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+
+        let mut profiles = Vec::new();
+        for level in [
+            OptimizationLevel::None,
+            OptimizationLevel::Coalesce,
+            OptimizationLevel::Idioms,
+            OptimizationLevel::Offsets,
+        ] {
+            let program = Program::compile(&program, level);
+            let mut input: &[u8] = &[];
+            let mut output = Vec::new();
+            let mut vm: VirtualMachine<u8> =
+                VirtualMachine::new(&program, None, None).with_profiling(true);
+            assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+            let profile = vm.profile().unwrap();
+            assert_eq!(profile.total(), vm.steps());
+            profiles.push(profile);
+        }
+        assert!(profiles.windows(2).all(|pair| pair[0] == pair[1]));
+
+        let profile = &profiles[0];
+        assert_eq!(profile.instructions()[8].executions, 6);
+        let loops: Vec<_> = profile
+            .loops()
+            .iter()
+            .map(|profile| {
+                (
+                    (profile.line, profile.position),
+                    profile.entries,
+                    profile.iterations,
+                    profile.executions,
+                )
+            })
+            .collect();
+        assert_eq!(
+            loops,
+            vec![((1, 4), 1, 3, 52), ((1, 8), 3, 6, 33), ((2, 3), 1, 6, 13)]
+        );
+        assert_eq!(profile.total(), 70);
+        assert_eq!(profile.loops()[0].share, 52.0 / 70.0);
+    }
+
+    #[test]
+    fn test_tape_dump_vm() {
+        let tmp_dir = TempDir::new("example").unwrap();
+
+        let extensions = Extensions { debug_dump: true };
+        let program = BrainFuckProgram::from_bytes_with_extensions(b"+++>++#\n>>#", extensions)
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Offsets);
+
+        let mut dumps = Vec::new();
+        for format in [DumpFormat::Text, DumpFormat::Json] {
+            let dump_path = tmp_dir.path().join("dump.txt");
+            let dump = TapeDump::new(File::create(&dump_path).unwrap(), format, 2);
+            let mut input: &[u8] = &[];
+            let mut output = Vec::new();
+            let mut vm: VirtualMachine<u8> =
+                VirtualMachine::new(&program, NonZeroUsize::new(5), None).with_tape_dump(dump);
+            assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+            assert_eq!(vm.steps(), 10);
+            dumps.push(fs::read_to_string(&dump_path).unwrap());
+        }
+        assert_eq!(
+            dumps,
+            vec![
+                "#[1:7] head 1 cells 0..=3: 3 [2] 0 0\n#[2:3] head 3 cells 1..=4: 2 0 [0] 0\n",
+                concat!(
+                    r#"{"line":1,"position":7,"head":1,"start":0,"cells":[3,2,0,0]}"#,
+                    "\n",
+                    r#"{"line":2,"position":3,"head":3,"start":1,"cells":[2,0,0,0]}"#,
+                    "\n"
+                ),
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_resume_vm() {
+        let program = BrainFuckProgram::from_str("++++++[>++++++++<-]>\n[>+>+<<-]>.>+++.[-]<<+[>]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+
+        for level in [
+            OptimizationLevel::None,
+            OptimizationLevel::Coalesce,
+            OptimizationLevel::Idioms,
+            OptimizationLevel::Offsets,
+        ] {
+            let program = Program::compile(&program, level);
+            let mut input: &[u8] = &[];
+            let mut expected = Vec::new();
+            let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+            assert!(matches!(vm.run(&mut input, &mut expected), Ok(())));
+            let total = vm.steps();
+            let tape = vm.tape.clone();
+
+            for budget in 0..total {
+                let mut output = Vec::new();
+                let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None)
+                    .with_max_steps(Some(budget))
+                    .with_eof_policy(EofPolicy::Zero);
+                assert!(matches!(
+                    vm.run(&mut input, &mut output),
+                    Err(VMError::FuelExhausted { steps, .. }) if steps == budget
+                ));
+                let mut file = Vec::new();
+                vm.snapshot().write(&mut file).unwrap();
+
+                let snapshot = Snapshot::read(&mut &file[..]).unwrap();
+                assert_eq!(snapshot.steps(), budget);
+                let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+                vm.restore(&snapshot).unwrap();
+                assert_eq!(vm.eof_policy, EofPolicy::Zero);
+                let mut vm = vm.with_max_steps(None);
+                assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+                assert_eq!(output, expected);
+                assert_eq!(vm.steps(), total);
+                assert_eq!(vm.tape, tape);
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot_resume_after_error_vm() {
+        struct BrokenReader;
+
+        impl Read for BrokenReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken reader"))
+            }
+        }
+
+        // Errors in the middle of the block, resumed with the input `A`. The block doesn't fit the
+        // tape of one cell, so the operations it is built from are executed.
+        for (content, cells, policy, broken, expected) in [
+            ("+.>,.", None, OverflowPolicy::Wrap, false, &b"A"[..]),
+            (
+                "+.>,.",
+                NonZeroUsize::new(1),
+                OverflowPolicy::Wrap,
+                false,
+                &b"A"[..],
+            ),
+            ("+.>,.", None, OverflowPolicy::Wrap, true, &b"A"[..]),
+            ("++>-<+.", None, OverflowPolicy::Trap, false, &b""[..]),
+        ] {
+            let program = BrainFuckProgram::from_str(content)
+                .unwrap()
+                .validate_brackets()
+                .unwrap();
+            let mut results = Vec::new();
+            for level in [
+                OptimizationLevel::None,
+                OptimizationLevel::Coalesce,
+                OptimizationLevel::Idioms,
+                OptimizationLevel::Offsets,
+            ] {
+                let program = Program::compile(&program, level);
+                let mut output = Vec::new();
+                let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, cells, Some(true))
+                    .with_overflow_policy(policy)
+                    .with_eof_policy(EofPolicy::Error);
+                let result = if broken {
+                    vm.run(&mut BrokenReader, &mut output)
+                } else {
+                    vm.run(&mut &b""[..], &mut output)
+                };
+                let error = result.unwrap_err().to_string();
+                let mut file = Vec::new();
+                vm.snapshot().write(&mut file).unwrap();
+
+                let snapshot = Snapshot::read(&mut &file[..]).unwrap();
+                let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+                vm.restore(&snapshot).unwrap();
+                let state = (vm.tape.clone(), vm.head, vm.steps);
+                let mut resumed = Vec::new();
+                let result = vm
+                    .run(&mut &b"A"[..], &mut resumed)
+                    .map_err(|err| err.to_string());
+                results.push((error, state, output, resumed, result, vm.steps));
+            }
+            assert_eq!(results[0].3, expected);
+            assert!(results.iter().all(|result| *result == results[0]));
+        }
+    }
+
+    #[test]
+    fn test_step_back_vm() {
+        let program = BrainFuckProgram::from_str(",>,[-<+>]<.\n>>>>[-]+")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+
+        for level in [
+            OptimizationLevel::None,
+            OptimizationLevel::Coalesce,
+            OptimizationLevel::Idioms,
+            OptimizationLevel::Offsets,
+        ] {
+            let program = Program::compile(&program, level);
+            let mut input: &[u8] = b"AB";
+            let mut output = Vec::new();
+            let mut vm: VirtualMachine<u8> =
+                VirtualMachine::new(&program, NonZeroUsize::new(2), Some(true)).with_history(1000);
+            let mut states = Vec::new();
+            while !vm.is_finished() {
+                states.push((vm.tape.clone(), vm.head, vm.instruction_pointer, vm.steps));
+                vm.step(&mut input, &mut output).unwrap();
+            }
+            assert_eq!(output, vec![131]);
+            let finished = (vm.tape.clone(), vm.head, vm.instruction_pointer, vm.steps);
+            assert_eq!(vm.history_len(), states.len());
+
+            for state in states.iter().rev() {
+                assert!(vm.step_back());
+                assert_eq!(
+                    (vm.tape.clone(), vm.head, vm.instruction_pointer, vm.steps),
+                    *state
+                );
+                assert_eq!(vm.tape_size, vm.tape.len());
+            }
+            assert!(!vm.step_back());
+
+            // The input is read by reverted operations again.
+            let mut input: &[u8] = &[];
+            assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+            assert_eq!(output, vec![131, 131]);
+            assert_eq!(
+                (vm.tape.clone(), vm.head, vm.instruction_pointer, vm.steps),
+                finished
+            );
+        }
+
+        let program = Program::compile(&program, OptimizationLevel::None);
+        let mut input: &[u8] = b"AB";
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None).with_history(3);
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(vm.history_len(), 3);
+        assert_eq!(
+            vm.last_write(4).map(|write| (write.before, write.after)),
+            Some((0, 1))
+        );
+        // The writes of the first cell are older than the history keeps.
+        assert_eq!(vm.last_write(0), None);
+
+        let mut input: &[u8] = b"AB";
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, None, None).with_history(1000);
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(
+            vm.last_write(1),
+            Some(CellWrite {
+                step: 329,
+                instruction_pointer: 4,
+                before: 1,
+                after: 0,
+            })
+        );
+        assert_eq!(
+            vm.last_write(0),
+            Some(CellWrite {
+                step: 331,
+                instruction_pointer: 6,
+                before: 130,
+                after: 131,
+            })
+        );
+        assert_eq!(vm.last_write(2), None);
+        assert!(vm.set_cell(2, 7));
+        assert_eq!(vm.history_len(), 0);
+    }
+
+    #[test]
+    fn test_step_back_block_writing_cell_twice() {
+        let program = BrainFuckProgram::from_str("++>+<+")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Offsets);
+        assert_eq!(program.instructions().len(), 1);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> =
+            VirtualMachine::new(&program, NonZeroUsize::new(2), None).with_history(10);
+        vm.step(&mut input, &mut output).unwrap();
+        assert_eq!(vm.tape, vec![3, 1]);
+        assert_eq!(
+            vm.last_write(0),
+            Some(CellWrite {
+                step: 0,
+                instruction_pointer: 0,
+                before: 0,
+                after: 3,
+            })
+        );
+
+        assert!(vm.step_back());
+        assert_eq!(vm.tape, vec![0, 0]);
+        assert_eq!((vm.head, vm.instruction_pointer, vm.steps), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_with_program_vm() {
+        let first = BrainFuckProgram::from_str("+++>++")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let first = Program::compile(&first, OptimizationLevel::Offsets);
+        let second = BrainFuckProgram::from_str("[-<+>]<.")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let second = Program::compile(&second, OptimizationLevel::Offsets);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&first, NonZeroUsize::new(4), None)
+            .with_history(10)
+            .with_profiling(true);
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert!(vm.history_len() > 0);
+
+        let mut vm = vm.with_program(&second);
+        assert_eq!(
+            (vm.head(), vm.instruction_pointer(), vm.history_len()),
+            (1, 0, 0)
+        );
+        assert!(matches!(vm.run(&mut input, &mut output), Ok(())));
+        assert_eq!(output, vec![5]);
+        assert_eq!(vm.steps(), 19);
+        assert_eq!(vm.profile().unwrap().total(), 13);
+
+        vm.reset();
+        assert_eq!(vm.tape, vec![0; 4]);
+        assert_eq!((vm.head(), vm.instruction_pointer(), vm.steps()), (0, 0, 0));
+        assert_eq!(vm.profile().unwrap().total(), 0);
+    }
+
+    #[test]
+    fn test_step_by_step_vm() {
+        let program = BrainFuckProgram::from_str("+++[>++<-]>.")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::None);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        for _ in 0..4 {
+            assert!(matches!(vm.step(&mut input, &mut output), Ok(())));
+        }
+        assert_eq!(vm.instruction_pointer(), 4);
+        assert_eq!(vm.head(), 0);
+        assert_eq!(vm.cell(0), Some(3));
+        assert!(vm.set_cell(0, 1));
+        assert!(!vm.set_cell(3000, 1));
+        while !vm.is_finished() {
+            assert!(matches!(vm.step(&mut input, &mut output), Ok(())));
+        }
+        assert!(matches!(vm.step(&mut input, &mut output), Ok(())));
+        assert_eq!(output, vec![2]);
+        assert_eq!(vm.steps(), 12);
+    }
+
+    #[test]
+    fn test_vm_error_diagnostic() {
+        let program = BrainFuckProgram::from_str("+++\n\t->>\n<<<<")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::Offsets);
+
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&program, None, None);
+        let err = vm.run(&mut input, &mut output).unwrap_err();
+        assert_eq!(
+            err.render(&program, false),
+            "error: previous element of the tape is not reachable\n \
+             --> <input>:3:3\n  \
+             |\n\
+             3 | <<<<\n  \
+             |   ^ head 0, cell value 2\n"
+        );
+
+        let mut bytes = Vec::new();
+        bytecode::write(&program, CellWidth::of::<u8>(), false, &mut bytes).unwrap();
+        let (_, loaded) = bytecode::read(&mut &bytes[..]).unwrap();
+        assert_eq!(loaded.source(), None);
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&loaded, None, None);
+        let err = vm.run(&mut input, &mut output).unwrap_err();
+        assert_eq!(
+            err.render(&loaded, false),
+            "error: previous element of the tape is not reachable\n \
+             = note: head 0, cell value 2\n"
+        );
     }
 }
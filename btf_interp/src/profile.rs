@@ -0,0 +1,185 @@
+//! Provide execution profile of BF program by instructions and loops of the source program.
+use crate::ir::{Op, Program};
+use std::cmp::Reverse;
+use std::io::{self, Write};
+
+/// Provide structure to represent executions of BF instruction of the source program.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct InstructionProfile {
+    /// Line of the file from where BF instruction is parsed.
+    pub line: usize,
+    /// Position at the line from where BF instruction is parsed.
+    pub position: usize,
+    /// The number of times BF instruction is executed.
+    pub executions: u64,
+}
+
+/// Provide structure to represent executions of the loop of the source program.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LoopProfile {
+    /// Line of `[` of the loop.
+    pub line: usize,
+    /// Position at the line of `[` of the loop.
+    pub position: usize,
+    /// Line of `]` of the loop.
+    pub end_line: usize,
+    /// Position at the line of `]` of the loop.
+    pub end_position: usize,
+    /// The number of times the loop is reached, which is the number of executions of `[`.
+    pub entries: u64,
+    /// The number of times the body of the loop is run, which is the number of executions of `]`.
+    pub iterations: u64,
+    /// The number of executions of BF instructions of the loop including nested loops.
+    pub executions: u64,
+    /// The share of executions of the loop in all executed BF instructions, from 0 to 1.
+    pub share: f64,
+}
+
+/// Provide structure to represent executions of BF instructions and loops of the source program.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Profile {
+    /// The number of executed BF instructions.
+    total: u64,
+    /// Executions of every BF instruction in the order of the source program.
+    instructions: Vec<InstructionProfile>,
+    /// Executions of every loop, the hottest first.
+    loops: Vec<LoopProfile>,
+}
+
+impl Profile {
+    /// Create profile of the program from the number of executions of every BF instruction.
+    pub(crate) fn new(program: &Program, executions: &[u64]) -> Profile {
+        let executions_of = |index: usize| executions.get(index).copied().unwrap_or(0);
+        let length = program.locations().len().max(executions.len()).max(
+            program
+                .instructions()
+                .last()
+                .map_or(0, |last| last.source().end),
+        );
+        let instructions: Vec<InstructionProfile> = (0..length)
+            .map(|index| {
+                let location = program.location(index);
+                InstructionProfile {
+                    line: location.line,
+                    position: location.position,
+                    executions: executions_of(index),
+                }
+            })
+            .collect();
+        let total = instructions.iter().fold(0u64, |total, instruction| {
+            total.saturating_add(instruction.executions)
+        });
+
+        let operations = program.instructions();
+        let mut loops: Vec<(usize, LoopProfile)> = Vec::new();
+        for operation in operations {
+            let Op::JumpIfZero(target) = *operation.op() else {
+                continue;
+            };
+            let open = operation.source().start;
+            let close = operations[target].source().start;
+            let loop_executions =
+                (open..=close).fold(0u64, |sum, index| sum.saturating_add(executions_of(index)));
+            let (start, end) = (program.location(open), program.location(close));
+            loops.push((
+                open,
+                LoopProfile {
+                    line: start.line,
+                    position: start.position,
+                    end_line: end.line,
+                    end_position: end.position,
+                    entries: executions_of(open),
+                    iterations: executions_of(close),
+                    executions: loop_executions,
+                    share: if total == 0 {
+                        0.0
+                    } else {
+                        loop_executions as f64 / total as f64
+                    },
+                },
+            ));
+        }
+        loops.sort_by_key(|&(open, profile)| (Reverse(profile.executions), open));
+
+        Profile {
+            total,
+            instructions,
+            loops: loops.into_iter().map(|(_, profile)| profile).collect(),
+        }
+    }
+
+    /// Get the number of executed BF instructions.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Get executions of every BF instruction in the order of the source program.
+    pub fn instructions(&self) -> &[InstructionProfile] {
+        &self.instructions[..]
+    }
+
+    /// Get executions of every loop, the hottest first.
+    pub fn loops(&self) -> &[LoopProfile] {
+        &self.loops[..]
+    }
+
+    /// Write table of `top` hottest loops, one loop per line.
+    pub fn write_table(&self, writer: &mut impl Write, top: usize) -> io::Result<()> {
+        writeln!(writer, "Executed {} instructions.", self.total)?;
+        writeln!(
+            writer,
+            "{:<20} {:>12} {:>14} {:>16} {:>8}",
+            "Loop", "Entries", "Iterations", "Instructions", "Share"
+        )?;
+        for profile in self.loops.iter().take(top) {
+            let span = format!(
+                "{}:{}-{}:{}",
+                profile.line, profile.position, profile.end_line, profile.end_position
+            );
+            writeln!(
+                writer,
+                "{:<20} {:>12} {:>14} {:>16} {:>7.2}%",
+                span,
+                profile.entries,
+                profile.iterations,
+                profile.executions,
+                profile.share * 100.0
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write profile as JSON object with the total, executions of instructions and of loops.
+    pub fn write_json(&self, writer: &mut impl Write) -> io::Result<()> {
+        write!(writer, "{{\"total\":{},\"instructions\":[", self.total)?;
+        for (index, profile) in self.instructions.iter().enumerate() {
+            if index > 0 {
+                write!(writer, ",")?;
+            }
+            write!(
+                writer,
+                "{{\"line\":{},\"position\":{},\"executions\":{}}}",
+                profile.line, profile.position, profile.executions
+            )?;
+        }
+        write!(writer, "],\"loops\":[")?;
+        for (index, profile) in self.loops.iter().enumerate() {
+            if index > 0 {
+                write!(writer, ",")?;
+            }
+            write!(
+                writer,
+                "{{\"line\":{},\"position\":{},\"end_line\":{},\"end_position\":{},\"entries\":{},\"iterations\":{},\"executions\":{},\"share\":{}}}",
+                profile.line,
+                profile.position,
+                profile.end_line,
+                profile.end_position,
+                profile.entries,
+                profile.iterations,
+                profile.executions,
+                profile.share
+            )?;
+        }
+        writeln!(writer, "]}}")
+    }
+}
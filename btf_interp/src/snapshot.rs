@@ -0,0 +1,457 @@
+//! Provide snapshots of the state of Virtual Machine to resume BF program later, even in another
+//! process.
+//!
+//! The file starts with magic number `BTFS`, format version as little-endian `u16`, number of bytes
+//! of the cell, `1` if the cell is signed or `0` otherwise and the hash of the program as
+//! little-endian `u64`. The header is followed by flags, where bit `0` means the tape is adjustable
+//! and bits `1` to `4` mean the optional values below are written, the encoding, overflow and end of
+//! input policies, the maximum size of the tape, the step limit, the head, the instruction pointer,
+//! the progress of the interrupted operation, the step count, the cells of the tape and the profiling
+//! counts. Numbers are written as LEB128 variable-length integers, cells are zigzag encoded.
+use crate::bytecode::{self, BytecodeError, CellWidth, Decoder};
+use crate::ir::Program;
+use crate::{CellEncoding, EofPolicy, OverflowPolicy};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Magic number snapshot files start with.
+pub const MAGIC: [u8; 4] = *b"BTFS";
+/// Version of snapshot format written by this library.
+pub const VERSION: u16 = 1;
+
+/// Flag set when the tape is adjustable.
+const ADJUST_TAPE_FLAG: u8 = 1;
+/// Flag set when the maximum size of the tape is written.
+const MAX_TAPE_SIZE_FLAG: u8 = 1 << 1;
+/// Flag set when the step limit is written.
+const MAX_STEPS_FLAG: u8 = 1 << 2;
+/// Flag set when the progress of the operation interrupted by the step limit is written.
+const RESUME_FLAG: u8 = 1 << 3;
+/// Flag set when the profiling counts are written.
+const PROFILING_FLAG: u8 = 1 << 4;
+
+/// Provide enum of errors for loading and restoring snapshots.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// IO error while reading the file.
+    IOError(io::Error),
+    /// Represent the case when the file doesn't start with the magic number.
+    InvalidMagic,
+    /// Represent the case when the file is written in another version of the format.
+    UnsupportedVersion(u16),
+    /// Represent the case when the file ends in the middle of the snapshot.
+    Truncated,
+    /// Represent the case when the snapshot in the file is malformed.
+    Corrupted(String),
+    /// Represent the case when the snapshot is taken for another program, by hashes of the programs.
+    ProgramMismatch { expected: u64, found: u64 },
+    /// Represent the case when the snapshot is taken for VM with another cell type.
+    CellMismatch {
+        expected: CellWidth,
+        found: CellWidth,
+    },
+}
+
+/// Provide human-readable format of the snapshot errors.
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::IOError(source) => write!(f, "IO error: {}", source),
+            SnapshotError::InvalidMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported snapshot version {}, expected {}",
+                version, VERSION
+            ),
+            SnapshotError::Truncated => write!(f, "snapshot file is truncated"),
+            SnapshotError::Corrupted(reason) => write!(f, "snapshot file is corrupted: {}", reason),
+            SnapshotError::ProgramMismatch { expected, found } => write!(
+                f,
+                "snapshot is taken for another program (hash {:016x}, expected {:016x})",
+                found, expected
+            ),
+            SnapshotError::CellMismatch { expected, found } => write!(
+                f,
+                "snapshot is taken for {} cells, expected {} cells",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::IOError(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::IOError(err)
+    }
+}
+
+/// Map errors of reading numbers shared with bytecode format.
+impl From<BytecodeError> for SnapshotError {
+    fn from(err: BytecodeError) -> Self {
+        match err {
+            BytecodeError::IOError(source) => SnapshotError::IOError(source),
+            BytecodeError::Truncated => SnapshotError::Truncated,
+            BytecodeError::Corrupted(reason) => SnapshotError::Corrupted(reason),
+            err => SnapshotError::Corrupted(err.to_string()),
+        }
+    }
+}
+
+/// Get hash of operations of the program with indices of BF instructions they are built from, which
+/// differs for different programs and for the same program compiled with another optimization level.
+pub fn program_hash(program: &Program) -> u64 {
+    let mut buffer = vec![program.level() as u8];
+    bytecode::write_instructions(&mut buffer, program.instructions());
+    // 64-bit FNV-1a, which is stable across platforms and versions of Rust unlike `DefaultHasher`.
+    buffer
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash: u64, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Provide structure to represent the state of Virtual Machine: the tape, the head, the instruction
+/// pointer, the step count and the options. Cancellation, deadline, tracer and tape dump aren't part
+/// of the state.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    /// Hash of the program the snapshot is taken for.
+    pub(crate) program_hash: u64,
+    /// The cell type of VM.
+    pub(crate) cell: CellWidth,
+    /// Whether the tape is adjustable.
+    pub(crate) adjust_tape: bool,
+    /// The maximum number of cells an adjustable tape can grow to.
+    pub(crate) max_tape_size: Option<usize>,
+    /// The rule to map cell values to bytes for `,` and `.` instructions.
+    pub(crate) encoding: CellEncoding,
+    /// The policy for `+` and `-` going past the bounds of the cell value.
+    pub(crate) overflow_policy: OverflowPolicy,
+    /// The policy for `,` when there is no more input.
+    pub(crate) eof_policy: EofPolicy,
+    /// The maximum number of BF instructions to execute.
+    pub(crate) max_steps: Option<u64>,
+    /// The pointer to the current element of tape.
+    pub(crate) head: usize,
+    /// The index of the operation to be executed next.
+    pub(crate) instruction_pointer: usize,
+    /// The index of the first BF instruction not executed yet of the operation interrupted by the step
+    /// limit.
+    pub(crate) resume_source: Option<usize>,
+    /// The number of BF instructions executed so far.
+    pub(crate) steps: u64,
+    /// Two's complement bits of values of the cells, sign extended to 64 bits.
+    pub(crate) cells: Vec<u64>,
+    /// The number of executions of every BF instruction, if profiling is enabled.
+    pub(crate) executions: Option<Vec<u64>>,
+}
+
+impl Snapshot {
+    /// Get hash of the program the snapshot is taken for, see [`program_hash`].
+    pub fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+
+    /// Get the cell type of VM the snapshot is taken for.
+    pub fn cell(&self) -> CellWidth {
+        self.cell
+    }
+
+    /// Get the number of BF instructions executed before the snapshot.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Get the pointer to the current element of tape.
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    /// Get the index of the operation to be executed next.
+    pub fn instruction_pointer(&self) -> usize {
+        self.instruction_pointer
+    }
+
+    /// Write the snapshot into snapshot file format.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&MAGIC);
+        buffer.extend_from_slice(&VERSION.to_le_bytes());
+        buffer.push(self.cell.bytes);
+        buffer.push(u8::from(self.cell.signed));
+        buffer.extend_from_slice(&self.program_hash.to_le_bytes());
+
+        let flags = [
+            (self.adjust_tape, ADJUST_TAPE_FLAG),
+            (self.max_tape_size.is_some(), MAX_TAPE_SIZE_FLAG),
+            (self.max_steps.is_some(), MAX_STEPS_FLAG),
+            (self.resume_source.is_some(), RESUME_FLAG),
+            (self.executions.is_some(), PROFILING_FLAG),
+        ]
+        .into_iter()
+        .filter(|&(set, _)| set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        buffer.push(flags);
+        buffer.push(match self.encoding {
+            CellEncoding::Byte => 0,
+            CellEncoding::Utf8 => 1,
+            CellEncoding::LittleEndian => 2,
+        });
+        buffer.push(match self.overflow_policy {
+            OverflowPolicy::Wrap => 0,
+            OverflowPolicy::Saturate => 1,
+            OverflowPolicy::Trap => 2,
+        });
+        buffer.push(match self.eof_policy {
+            EofPolicy::Unchanged => 0,
+            EofPolicy::Zero => 1,
+            EofPolicy::MinusOne => 2,
+            EofPolicy::Error => 3,
+        });
+        if let Some(max_tape_size) = self.max_tape_size {
+            bytecode::write_unsigned(&mut buffer, max_tape_size as u64);
+        }
+        if let Some(max_steps) = self.max_steps {
+            bytecode::write_unsigned(&mut buffer, max_steps);
+        }
+        bytecode::write_unsigned(&mut buffer, self.head as u64);
+        bytecode::write_unsigned(&mut buffer, self.instruction_pointer as u64);
+        if let Some(resume_source) = self.resume_source {
+            bytecode::write_unsigned(&mut buffer, resume_source as u64);
+        }
+        bytecode::write_unsigned(&mut buffer, self.steps);
+
+        bytecode::write_unsigned(&mut buffer, self.cells.len() as u64);
+        for &bits in &self.cells {
+            bytecode::write_signed(&mut buffer, bits as i64);
+        }
+        if let Some(executions) = &self.executions {
+            bytecode::write_unsigned(&mut buffer, executions.len() as u64);
+            for &count in executions {
+                bytecode::write_unsigned(&mut buffer, count);
+            }
+        }
+        writer.write_all(&buffer)
+    }
+
+    /// Read the snapshot from snapshot file format.
+    pub fn read(reader: &mut impl Read) -> Result<Snapshot, SnapshotError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut decoder = Decoder {
+            bytes: &bytes,
+            position: 0,
+        };
+
+        if decoder.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let version = u16::from_le_bytes([decoder.byte()?, decoder.byte()?]);
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let cell_bytes = decoder.byte()?;
+        if !matches!(cell_bytes, 1 | 2 | 4 | 8) {
+            return Err(corrupted("invalid cell width"));
+        }
+        let signed = match decoder.byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(corrupted("invalid cell sign")),
+        };
+        let mut hash = [0; 8];
+        let hash_bytes = decoder.bytes(hash.len())?;
+        hash.copy_from_slice(hash_bytes);
+
+        let flags = decoder.byte()?;
+        let known_flags =
+            ADJUST_TAPE_FLAG | MAX_TAPE_SIZE_FLAG | MAX_STEPS_FLAG | RESUME_FLAG | PROFILING_FLAG;
+        if flags & !known_flags != 0 {
+            return Err(corrupted("unknown flags"));
+        }
+        let encoding = match decoder.byte()? {
+            0 => CellEncoding::Byte,
+            1 => CellEncoding::Utf8,
+            2 => CellEncoding::LittleEndian,
+            _ => return Err(corrupted("invalid cell encoding")),
+        };
+        let overflow_policy = match decoder.byte()? {
+            0 => OverflowPolicy::Wrap,
+            1 => OverflowPolicy::Saturate,
+            2 => OverflowPolicy::Trap,
+            _ => return Err(corrupted("invalid overflow policy")),
+        };
+        let eof_policy = match decoder.byte()? {
+            0 => EofPolicy::Unchanged,
+            1 => EofPolicy::Zero,
+            2 => EofPolicy::MinusOne,
+            3 => EofPolicy::Error,
+            _ => return Err(corrupted("invalid end of input policy")),
+        };
+        let max_tape_size = if flags & MAX_TAPE_SIZE_FLAG != 0 {
+            Some(decoder.length()?)
+        } else {
+            None
+        };
+        let max_steps = if flags & MAX_STEPS_FLAG != 0 {
+            Some(decoder.unsigned()?)
+        } else {
+            None
+        };
+        let head = decoder.length()?;
+        let instruction_pointer = decoder.length()?;
+        let resume_source = if flags & RESUME_FLAG != 0 {
+            Some(decoder.length()?)
+        } else {
+            None
+        };
+        let steps = decoder.unsigned()?;
+
+        let count = decoder.length()?;
+        // Every number takes at least 1 byte, so a wrong count can't allocate too much memory.
+        let mut cells = Vec::with_capacity(count.min(bytes.len()));
+        for _ in 0..count {
+            cells.push(decoder.signed()? as u64);
+        }
+        if head >= cells.len() {
+            return Err(corrupted("the head is out of the tape"));
+        }
+        let executions = if flags & PROFILING_FLAG != 0 {
+            let count = decoder.length()?;
+            let mut executions = Vec::with_capacity(count.min(bytes.len()));
+            for _ in 0..count {
+                executions.push(decoder.unsigned()?);
+            }
+            Some(executions)
+        } else {
+            None
+        };
+        if decoder.position != bytes.len() {
+            return Err(corrupted("unexpected data after the snapshot"));
+        }
+
+        Ok(Snapshot {
+            program_hash: u64::from_le_bytes(hash),
+            cell: CellWidth {
+                bytes: cell_bytes,
+                signed,
+            },
+            adjust_tape: flags & ADJUST_TAPE_FLAG != 0,
+            max_tape_size,
+            encoding,
+            overflow_policy,
+            eof_policy,
+            max_steps,
+            head,
+            instruction_pointer,
+            resume_source,
+            steps,
+            cells,
+            executions,
+        })
+    }
+}
+
+/// Create error for malformed snapshot.
+fn corrupted(reason: &str) -> SnapshotError {
+    SnapshotError::Corrupted(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::CellWidth;
+    use crate::ir::{OptimizationLevel, Program};
+    use crate::snapshot::{Snapshot, SnapshotError};
+    use crate::VirtualMachine;
+    use btf_types::BrainFuckProgram;
+    use std::num::NonZeroUsize;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let program = BrainFuckProgram::from_str("-->+++[>+<-]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let program = Program::compile(&program, OptimizationLevel::None);
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        let mut vm: VirtualMachine<i32> =
+            VirtualMachine::new(&program, NonZeroUsize::new(4), Some(true))
+                .with_max_tape_size(NonZeroUsize::new(64))
+                .with_max_steps(Some(9))
+                .with_profiling(true);
+        assert!(vm.run(&mut input, &mut output).is_err());
+
+        let snapshot = vm.snapshot();
+        assert_eq!(snapshot.cells, vec![-2i64 as u64, 3, 1, 0]);
+        assert_eq!((snapshot.head(), snapshot.instruction_pointer()), (2, 9));
+        let mut file = Vec::new();
+        snapshot.write(&mut file).unwrap();
+        assert_eq!(&file[..4], b"BTFS");
+        assert_eq!(Snapshot::read(&mut &file[..]).unwrap(), snapshot);
+
+        assert!(matches!(
+            Snapshot::read(&mut &file[..file.len() - 1]),
+            Err(SnapshotError::Truncated)
+        ));
+        assert!(matches!(
+            Snapshot::read(&mut &b"BTFC"[..]),
+            Err(SnapshotError::InvalidMagic)
+        ));
+        let mut unknown_version = file.clone();
+        unknown_version[4] = 9;
+        assert!(matches!(
+            Snapshot::read(&mut &unknown_version[..]),
+            Err(SnapshotError::UnsupportedVersion(9))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_mismatch() {
+        let program = BrainFuckProgram::from_str("+[->+<]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let other = BrainFuckProgram::from_str("+[->++<]")
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        let compiled = Program::compile(&program, OptimizationLevel::None);
+        let snapshot = VirtualMachine::<u8>::new(&compiled, None, None).snapshot();
+
+        let other = Program::compile(&other, OptimizationLevel::None);
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&other, None, None);
+        assert!(matches!(
+            vm.restore(&snapshot),
+            Err(SnapshotError::ProgramMismatch { .. })
+        ));
+
+        let optimized = Program::compile(&program, OptimizationLevel::Idioms);
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&optimized, None, None);
+        assert!(matches!(
+            vm.restore(&snapshot),
+            Err(SnapshotError::ProgramMismatch { .. })
+        ));
+
+        let mut vm: VirtualMachine<u16> = VirtualMachine::new(&compiled, None, None);
+        assert!(matches!(
+            vm.restore(&snapshot),
+            Err(SnapshotError::CellMismatch { expected, found })
+                if expected == CellWidth::of::<u16>() && found == CellWidth::of::<u8>()
+        ));
+
+        let mut vm: VirtualMachine<u8> = VirtualMachine::new(&compiled, None, None);
+        assert!(vm.restore(&snapshot).is_ok());
+    }
+}
@@ -0,0 +1,173 @@
+//! Provide tracing of executed operations into JSON Lines.
+use std::fmt;
+use std::io::{self, Write};
+use std::num::NonZeroU64;
+use std::ops::RangeInclusive;
+
+/// Provide structure to select operations written into the trace.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceFilter {
+    /// Lines of the source file, operations starting at them are traced. All lines if `None`.
+    pub lines: Option<RangeInclusive<usize>>,
+    /// Step numbers, operations starting at them are traced. All steps if `None`.
+    pub steps: Option<RangeInclusive<u64>>,
+    /// Trace only every Nth operation of ones selected by lines and steps, starting from the first.
+    pub every: NonZeroU64,
+}
+
+/// Trace every operation by default.
+impl Default for TraceFilter {
+    fn default() -> Self {
+        TraceFilter {
+            lines: None,
+            steps: None,
+            every: NonZeroU64::MIN,
+        }
+    }
+}
+
+/// Provide structure to represent one executed operation in the trace.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    /// The number of BF instructions executed before the operation.
+    pub step: u64,
+    /// Line of the first BF instruction the operation is built from.
+    pub line: usize,
+    /// Position at the line of the first BF instruction the operation is built from.
+    pub position: usize,
+    /// Human-readable format of the operation.
+    pub instruction: String,
+    /// The pointer to the current element of tape before the operation.
+    pub head: usize,
+    /// Value of the current cell before the operation.
+    pub before: i128,
+    /// Value of the current cell after the operation, the head may point to another cell.
+    pub after: i128,
+}
+
+/// Provide JSON object format of the record, without line break.
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{\"step\":{},\"line\":{},\"position\":{},\"instruction\":\"",
+            self.step, self.line, self.position
+        )?;
+        for symbol in self.instruction.chars() {
+            match symbol {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                symbol if symbol.is_control() => write!(f, "\\u{:04x}", symbol as u32)?,
+                symbol => write!(f, "{}", symbol)?,
+            }
+        }
+        write!(
+            f,
+            "\",\"head\":{},\"before\":{},\"after\":{}}}",
+            self.head, self.before, self.after
+        )
+    }
+}
+
+/// Provide structure to write records of executed operations one per line.
+///
+/// Optimized programs are traced by operations which stand for many BF instructions, compile the
+/// program with `OptimizationLevel::None` to trace every BF instruction.
+pub struct Tracer {
+    /// Destination of the trace.
+    writer: Box<dyn Write>,
+    /// Rules to select operations written into the trace.
+    filter: TraceFilter,
+    /// The number of operations selected by lines and steps so far.
+    selected: u64,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("filter", &self.filter)
+            .field("selected", &self.selected)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    /// Create tracer writing records selected by the filter into `writer`.
+    pub fn new(writer: impl Write + 'static, filter: TraceFilter) -> Tracer {
+        Tracer {
+            writer: Box::new(writer),
+            filter,
+            selected: 0,
+        }
+    }
+
+    /// Check if the operation starting at the step and the line of the source file is traced.
+    pub(crate) fn accepts(&mut self, step: u64, line: usize) -> bool {
+        let in_lines = self
+            .filter
+            .lines
+            .as_ref()
+            .is_none_or(|lines| lines.contains(&line));
+        let in_steps = self
+            .filter
+            .steps
+            .as_ref()
+            .is_none_or(|steps| steps.contains(&step));
+        if !in_lines || !in_steps {
+            return false;
+        }
+        self.selected += 1;
+        (self.selected - 1).is_multiple_of(self.filter.every.get())
+    }
+
+    /// Write the record as one line.
+    pub(crate) fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        writeln!(self.writer, "{}", record)
+    }
+
+    /// Flush records written so far into the destination.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::{TraceFilter, TraceRecord, Tracer};
+    use std::io;
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn test_trace_record_json() {
+        let record = TraceRecord {
+            step: 3,
+            line: 1,
+            position: 4,
+            instruction: "Say \"hi\"".to_string(),
+            head: 2,
+            before: -1,
+            after: 0,
+        };
+        assert_eq!(
+            record.to_string(),
+            r#"{"step":3,"line":1,"position":4,"instruction":"Say \"hi\"","head":2,"before":-1,"after":0}"#
+        );
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let mut tracer = Tracer::new(
+            io::sink(),
+            TraceFilter {
+                lines: Some(2..=3),
+                steps: Some(10..=100),
+                every: NonZeroU64::new(2).unwrap(),
+            },
+        );
+        let accepted: Vec<bool> = [(5, 2), (10, 1), (10, 2), (11, 3), (12, 3), (101, 2)]
+            .into_iter()
+            .map(|(step, line)| tracer.accepts(step, line))
+            .collect();
+        assert_eq!(accepted, vec![false, false, true, false, true, false]);
+    }
+}
@@ -0,0 +1,289 @@
+//! Provide compiler-style diagnostics showing the source line with the caret under the offending
+//! instruction, shared by errors of parsing and executing BF programs.
+use crate::Span;
+use std::env;
+use std::fmt::Write;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+
+/// ANSI escape sequence of bold red text, used for errors and primary labels.
+const RED: &str = "\x1b[1;31m";
+/// ANSI escape sequence of bold blue text, used for the gutter and secondary labels.
+const BLUE: &str = "\x1b[1;34m";
+/// ANSI escape sequence of bold text, used for the message.
+const BOLD: &str = "\x1b[1m";
+/// ANSI escape sequence resetting the style.
+const RESET: &str = "\x1b[0m";
+
+/// Check if diagnostics written into stderr should be colored: stderr is a terminal and `NO_COLOR`
+/// environment variable is not set.
+pub fn use_color() -> bool {
+    io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+/// Provide structure to represent the annotated part of the source line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Label {
+    /// Line of the annotated text, counted from 1.
+    line: usize,
+    /// Column of the start of the annotated text, counted from 1.
+    column: usize,
+    /// Number of columns underlined.
+    width: usize,
+    /// Text shown after the underline.
+    message: String,
+}
+
+impl Label {
+    /// Create label underlining `width` columns at the line starting with the column.
+    pub fn new(line: usize, column: usize, width: usize, message: impl Into<String>) -> Label {
+        Label {
+            line,
+            column,
+            width,
+            message: message.into(),
+        }
+    }
+
+    /// Create label underlining the source text of the span.
+    pub fn from_span(span: Span, message: impl Into<String>) -> Label {
+        Label::new(span.line, span.column, span.length, message)
+    }
+
+    /// Get line of the annotated text.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Get column of the start of the annotated text.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+/// Provide structure to represent the error with labels pointing into the source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    /// Short description of the error.
+    message: String,
+    /// Label of the place where the error happened, underlined with `^`.
+    primary: Option<Label>,
+    /// Labels of related places, underlined with `-`.
+    secondary: Vec<Label>,
+    /// Notes shown after the source lines.
+    notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Create diagnostic of the error without labels.
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            message: message.into(),
+            primary: None,
+            secondary: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    /// Set the label of the place where the error happened.
+    pub fn with_primary(mut self, label: Label) -> Self {
+        self.primary = Some(label);
+        self
+    }
+
+    /// Add the label of the place related to the error.
+    pub fn with_secondary(mut self, label: Label) -> Self {
+        self.secondary.push(label);
+        self
+    }
+
+    /// Add the note shown after the source lines.
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Get short description of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Get the label of the place where the error happened.
+    pub fn primary(&self) -> Option<&Label> {
+        self.primary.as_ref()
+    }
+
+    /// Get labels of places related to the error.
+    pub fn secondary(&self) -> &[Label] {
+        &self.secondary[..]
+    }
+
+    /// Render the diagnostic of the error in the file, with annotated lines of the source if it is
+    /// known, and with ANSI colors if `color`.
+    ///
+    /// ```text
+    /// error: no close bracket found matching bracket
+    ///  --> program.bf:2:7
+    ///   |
+    /// 2 | ><+-.,[[]
+    ///   |       ^ unclosed bracket
+    ///   |         - the last `]` after it closes a nested `[`
+    /// ```
+    pub fn render(&self, filename: &Path, source: Option<&[u8]>, color: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if color {
+                format!("{}{}{}", style, text, RESET)
+            } else {
+                text.to_string()
+            }
+        };
+        let mut output = String::new();
+        let _ = writeln!(
+            output,
+            "{}{}",
+            paint(RED, "error"),
+            paint(BOLD, &format!(": {}", self.message))
+        );
+
+        let mut labels: Vec<(&Label, bool)> =
+            self.primary.iter().map(|label| (label, true)).collect();
+        labels.extend(self.secondary.iter().map(|label| (label, false)));
+        let gutter = labels
+            .iter()
+            .map(|(label, _)| label.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let blank = " ".repeat(gutter);
+        if let Some(primary) = &self.primary {
+            let _ = writeln!(
+                output,
+                "{}{} {}:{}:{}",
+                blank,
+                paint(BLUE, "-->"),
+                filename.display(),
+                primary.line,
+                primary.column
+            );
+        }
+
+        if let Some(source) = source.filter(|_| !labels.is_empty()) {
+            let _ = writeln!(output, "{} {}", blank, paint(BLUE, "|"));
+            labels.sort_by_key(|(label, _)| (label.line, label.column));
+            let mut previous: Option<usize> = None;
+            for &(label, primary) in &labels {
+                let Some(text) = source_line(source, label.line) else {
+                    continue;
+                };
+                if previous != Some(label.line) {
+                    if previous.is_some_and(|line| label.line > line + 1) {
+                        let _ = writeln!(output, "{}", paint(BLUE, "..."));
+                    }
+                    let _ = writeln!(
+                        output,
+                        "{} {}",
+                        paint(BLUE, &format!("{:>width$} |", label.line, width = gutter)),
+                        text
+                    );
+                    previous = Some(label.line);
+                }
+                let padding: String = text
+                    .chars()
+                    .take(label.column.saturating_sub(1))
+                    .map(|char| if char == '\t' { '\t' } else { ' ' })
+                    .collect();
+                let (marker, style) = if primary { ('^', RED) } else { ('-', BLUE) };
+                let mut annotation: String =
+                    std::iter::repeat_n(marker, label.width.max(1)).collect();
+                if !label.message.is_empty() {
+                    annotation.push(' ');
+                    annotation.push_str(&label.message);
+                }
+                let _ = writeln!(
+                    output,
+                    "{} {} {}{}",
+                    blank,
+                    paint(BLUE, "|"),
+                    padding,
+                    paint(style, &annotation)
+                );
+            }
+            // Separate source lines from notes like rustc does.
+            if !self.notes.is_empty() {
+                let _ = writeln!(output, "{} {}", blank, paint(BLUE, "|"));
+            }
+        }
+
+        for note in &self.notes {
+            let _ = writeln!(output, "{} {} note: {}", blank, paint(BLUE, "="), note);
+        }
+        output
+    }
+}
+
+/// Get the line of the source counted from 1 without the line end, where every byte which is not a
+/// part of valid UTF-8 character is replaced with `U+FFFD`, so it takes one column like in spans.
+fn source_line(source: &[u8], line: usize) -> Option<String> {
+    let bytes = source
+        .split(|&byte| byte == b'\n')
+        .nth(line.checked_sub(1)?)?;
+    let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        text.extend(std::iter::repeat_n(
+            char::REPLACEMENT_CHARACTER,
+            chunk.invalid().len(),
+        ));
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::diagnostic::{Diagnostic, Label};
+
+    #[test]
+    fn test_render_diagnostic() {
+        let source = b"+\t[\xff+\n\n\n[]]\r\n";
+        let diagnostic = Diagnostic::error("no open bracket found matching bracket")
+            .with_primary(Label::new(4, 3, 1, "unmatched bracket"))
+            .with_secondary(Label::new(1, 5, 1, ""))
+            .with_secondary(Label::new(4, 1, 1, "this `[` is already closed"))
+            .with_note("head 0, cell value 0");
+        assert_eq!(
+            diagnostic.render(Path::new("test.bf"), Some(source), false),
+            "error: no open bracket found matching bracket\n \
+             --> test.bf:4:3\n  \
+             |\n\
+             1 | +\t[\u{fffd}+\n  \
+             |  \t  -\n\
+             ...\n\
+             4 | []]\n  \
+             | - this `[` is already closed\n  \
+             |   ^ unmatched bracket\n  \
+             |\n  \
+             = note: head 0, cell value 0\n"
+        );
+
+        assert_eq!(
+            diagnostic.render(Path::new("test.bf"), None, false),
+            "error: no open bracket found matching bracket\n \
+             --> test.bf:4:3\n  \
+             = note: head 0, cell value 0\n"
+        );
+        let colored = diagnostic.render(Path::new("test.bf"), Some(source), true);
+        assert!(colored.starts_with("\x1b[1;31merror\x1b[0m"));
+        assert!(colored.contains("\x1b[1;31m^ unmatched bracket\x1b[0m"));
+    }
+
+    #[test]
+    fn test_render_diagnostic_without_labels() {
+        let diagnostic = Diagnostic::error("cancelled").with_note("head 3, cell value 1");
+        assert_eq!(
+            diagnostic.render(Path::new("test.bf"), Some(b"+[]"), false),
+            "error: cancelled\n = note: head 3, cell value 1\n"
+        );
+    }
+}
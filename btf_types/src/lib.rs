@@ -1,9 +1,19 @@
 //! Provide types implementation for BF interpreter.
-use std::cmp::Ordering;
+pub mod diagnostic;
+
+use diagnostic::{Diagnostic, Label};
+use std::convert::Infallible;
 use std::error::Error;
 use std::fmt;
 use std::fs;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Name of BF program parsed from string, bytes or reader, until it is set by
+/// [`BrainFuckProgram::with_filename`].
+pub const UNNAMED: &str = "<input>";
 
 /// Provide enum for all possible BF language instructions.
 #[derive(Debug, PartialEq)]
@@ -24,6 +34,25 @@ pub enum RawInstructions {
     ZeroJump,
     /// Represent `]` symbol. If the byte at the data pointer is nonzero, then instead of moving the instruction pointer forward to the next command, jump it back to the command after the matching [ command.
     NonZeroJump,
+    /// Represent `#` symbol of the debug dump extension. Dump the tape around the data pointer.
+    DebugDump,
+}
+
+/// Provide structure to enable extension instructions, all of them are comments by default.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Extensions {
+    /// Parse `#` as instruction dumping the tape instead of comment.
+    pub debug_dump: bool,
+}
+
+impl RawInstructions {
+    /// Convert char into BF language instruction or enabled extension instruction.
+    pub fn parse(symbol: char, extensions: Extensions) -> Option<RawInstructions> {
+        match symbol {
+            '#' if extensions.debug_dump => Some(RawInstructions::DebugDump),
+            _ => RawInstructions::try_from(symbol).ok(),
+        }
+    }
 }
 
 /// Try to convert char into BF language instruction.
@@ -57,19 +86,58 @@ impl fmt::Display for RawInstructions {
             RawInstructions::AcceptByte => write!(f, "Accept byte"),
             RawInstructions::ZeroJump => write!(f, "Zero jump"),
             RawInstructions::NonZeroJump => write!(f, "Non zero jump"),
+            RawInstructions::DebugDump => write!(f, "Debug dump"),
         }
     }
 }
 
+/// Provide identifier of the source BF program is parsed from, unique for every parsed program.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct FileId(u32);
+
+impl FileId {
+    /// Allocate identifier which is not used by any other source.
+    fn next() -> FileId {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        FileId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Get number of the identifier.
+    pub fn index(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Provide structure to represent the exact location of the source text of BF instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Span {
+    /// Identifier of the source.
+    pub file: FileId,
+    /// Offset in bytes from the start of the source.
+    pub start: usize,
+    /// Length in bytes.
+    pub length: usize,
+    /// Line of the start, counted from 1.
+    pub line: usize,
+    /// Column of the start at the line counted from 1, in characters for valid UTF-8 and in bytes
+    /// otherwise.
+    pub column: usize,
+}
+
+impl Span {
+    /// Get offset in bytes from the start of the source right after the span.
+    pub fn end(&self) -> usize {
+        self.start + self.length
+    }
+}
+
 /// Provide structure to represent location of BF instruction in file.
 #[derive(Debug)]
 pub struct IntructionPosition {
     /// BF instruction.
     instruction: RawInstructions,
-    /// Line of the file from where BF instruction is parsed.
-    line: usize,
-    /// Positoin at the line from where BF instruction is parsed.
-    position: usize,
+    /// Source text from where BF instruction is parsed.
+    span: Span,
 }
 
 impl IntructionPosition {
@@ -78,21 +146,35 @@ impl IntructionPosition {
         &self.instruction
     }
 
+    /// Get the source text of parsed instruction.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
     /// Get line of parsed instruction.
     pub fn line(&self) -> usize {
-        self.line
+        self.span.line
     }
 
     /// Get position at the line of parsed instruction.
     pub fn position(&self) -> usize {
-        self.position
+        self.span.column
+    }
+
+    /// Get offset in bytes from the start of the file of parsed instruction.
+    pub fn offset(&self) -> usize {
+        self.span.start
     }
 }
 
 /// Provide human-readable format of the instruction with position in parsed file.
 impl fmt::Display for IntructionPosition {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}] {}", self.line, self.position, self.instruction)
+        write!(
+            f,
+            "{}:{}] {}",
+            self.span.line, self.span.column, self.instruction
+        )
     }
 }
 
@@ -101,109 +183,320 @@ impl fmt::Display for IntructionPosition {
 pub struct BrainFuckProgram {
     /// Name of the file from where program is parsed.
     filename: PathBuf,
+    /// Identifier of the source in spans of instructions.
+    file: FileId,
+    /// Content of the file, to show its lines in diagnostics.
+    source: Vec<u8>,
     /// List of instructions with location parsed from file.
     instructions: Vec<IntructionPosition>,
 }
 
 impl BrainFuckProgram {
-    /// Create BF program based on the name of the file and it's content.
-    fn new(filename: impl AsRef<Path>, content: String) -> Self {
+    /// Create BF program based on the name of the file and it's content, with extension instructions.
+    ///
+    /// The content doesn't have to be UTF-8 encoded, as bytes other than instructions are comments.
+    /// Positions at the line are counted in characters, while every byte which is not a part of valid
+    /// UTF-8 character is counted as one position.
+    pub fn new(
+        filename: impl AsRef<Path>,
+        content: impl AsRef<[u8]>,
+        extensions: Extensions,
+    ) -> Self {
+        let file = FileId::next();
+        let source = content.as_ref().to_vec();
         let mut instructions: Vec<IntructionPosition> = Vec::new();
 
-        let mut line: usize = 1;
-        let mut position: usize = 1;
-        for char in content.chars() {
-            if char == '\n' {
-                line += 1;
-                position = 0;
-            }
-            match RawInstructions::try_from(char) {
-                Ok(instruction) => {
-                    let instruction_position = IntructionPosition {
-                        instruction,
-                        line,
-                        position,
-                    };
-                    instructions.push(instruction_position);
+        let mut offset: usize = 0;
+        for (index, line) in source.split(|&byte| byte == b'\n').enumerate() {
+            // Every line starts at column 1, `\r` of CRLF line end is the last column of its line.
+            let mut column: usize = 1;
+            for chunk in line.utf8_chunks() {
+                for (start, char) in chunk.valid().char_indices() {
+                    if let Some(instruction) = RawInstructions::parse(char, extensions) {
+                        let instruction_position = IntructionPosition {
+                            instruction,
+                            span: Span {
+                                file,
+                                start: offset + start,
+                                length: char.len_utf8(),
+                                line: index + 1,
+                                column,
+                            },
+                        };
+                        instructions.push(instruction_position);
+                    }
+                    column += 1;
                 }
-                Err(_e) => {}
+                column += chunk.invalid().len();
+                offset += chunk.valid().len() + chunk.invalid().len();
             }
-            position += 1;
+            // Skip the new line symbol.
+            offset += 1;
         }
         BrainFuckProgram {
             filename: filename.as_ref().to_path_buf(),
+            file,
+            source,
             instructions,
         }
     }
 
+    /// Set the name shown in error messages and locations, e.g. for program parsed from string.
+    pub fn with_filename(mut self, filename: impl AsRef<Path>) -> Self {
+        self.filename = filename.as_ref().to_path_buf();
+        self
+    }
+
     /// Get name of the file from where BF program is parsed.
     pub fn filename(&self) -> &Path {
         &self.filename
     }
 
+    /// Get identifier of the source in spans of instructions.
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    /// Get content of the file from where BF program is parsed.
+    pub fn source(&self) -> &[u8] {
+        &self.source[..]
+    }
+
     /// Get list of instructions for BF program.
     pub fn instructions(&self) -> &[IntructionPosition] {
         &self.instructions[..]
     }
 
+    /// Parse BF program from bytes, which don't have to be UTF-8 encoded.
+    pub fn from_bytes(bytes: &[u8]) -> BrainFuckProgram {
+        Self::from_bytes_with_extensions(bytes, Extensions::default())
+    }
+
+    /// Parse BF program from bytes with extension instructions.
+    pub fn from_bytes_with_extensions(bytes: &[u8], extensions: Extensions) -> BrainFuckProgram {
+        Self::new(UNNAMED, bytes, extensions)
+    }
+
+    /// Parse BF program read from the reader to its end, e.g. from stdin.
+    pub fn from_reader(reader: impl Read) -> io::Result<BrainFuckProgram> {
+        Self::from_reader_with_extensions(reader, Extensions::default())
+    }
+
+    /// Parse BF program read from the reader to its end with extension instructions.
+    pub fn from_reader_with_extensions(
+        mut reader: impl Read,
+        extensions: Extensions,
+    ) -> io::Result<BrainFuckProgram> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        Ok(Self::new(UNNAMED, content, extensions))
+    }
+
     /// Parse BF program from file.
     pub fn from_file<T: AsRef<Path>>(file_path: T) -> Result<BrainFuckProgram, Box<dyn Error>> {
+        Self::from_file_with_extensions(file_path, Extensions::default())
+    }
+
+    /// Parse BF program from file with extension instructions.
+    pub fn from_file_with_extensions<T: AsRef<Path>>(
+        file_path: T,
+        extensions: Extensions,
+    ) -> Result<BrainFuckProgram, Box<dyn Error>> {
         let file_path_ref = file_path.as_ref();
-        let content = fs::read_to_string(file_path_ref)?;
-        let bf_program = Self::new(file_path_ref, content);
+        let content = fs::read(file_path_ref)?;
+        let bf_program = Self::new(file_path_ref, content, extensions);
         Ok(bf_program)
     }
 
-    /// Validate if brackets are balanced.
-    pub fn validate_brackets(&self) -> Result<(), String> {
-        let mut opened_brackets = Vec::new();
-        let mut closed_brackets = Vec::new();
-        for instruction_position in self.instructions() {
+    /// Validate if brackets are balanced and match every bracket with its partner.
+    pub fn validate_brackets(self) -> Result<ValidatedProgram, BracketError> {
+        let mut jumps: Vec<Option<usize>> = vec![None; self.instructions.len()];
+        let mut opened_brackets: Vec<usize> = Vec::new();
+        // The last matched pair of brackets, to point to it from the error.
+        let mut last_pair: Option<(usize, usize)> = None;
+        for (index, instruction_position) in self.instructions().iter().enumerate() {
             match instruction_position.instruction() {
-                RawInstructions::ZeroJump => opened_brackets.push(instruction_position),
-                RawInstructions::NonZeroJump => {
-                    closed_brackets.push(instruction_position);
-                    if opened_brackets.len() < closed_brackets.len() {
-                        return Err(format!("Error in input file {}, no open bracket found matching bracket at line {} column {}.", self.filename.display(), instruction_position.line(), instruction_position.position()));
+                RawInstructions::ZeroJump => opened_brackets.push(index),
+                RawInstructions::NonZeroJump => match opened_brackets.pop() {
+                    Some(open_index) => {
+                        jumps[open_index] = Some(index);
+                        jumps[index] = Some(open_index);
+                        last_pair = Some((open_index, index));
                     }
-                }
+                    None => {
+                        return Err(BracketError {
+                            program: self,
+                            index,
+                            related: last_pair.map(|(open_index, _)| open_index),
+                        })
+                    }
+                },
                 _ => {}
             }
         }
 
-        match opened_brackets.len().cmp(&closed_brackets.len()) {
-            Ordering::Greater => {
-                let first_bracket = opened_brackets.first();
-                match first_bracket {
-                    None => return Err(format!("Error in input file {}, no open brackets found.", self.filename.display())),
-                    Some(bracket) => return Err(format!("Error in input file {}, no close bracket found matching bracket at line {} column {}.", self.filename.display(), bracket.line(), bracket.position())),
-                }
-            }
-            Ordering::Less => {
-                let last_bracket = closed_brackets.last();
-                match last_bracket {
-                    None => return Err(format!("Error in input file {}, no closed brackets found.", self.filename.display())),
-                    Some(bracket) => return Err(format!("Error in input file {}, no open bracket found matching bracket at line {} column {}.", self.filename.display(), bracket.line(), bracket.position())),
-                }
-            }
-            Ordering::Equal => {}
+        if let Some(&open_index) = opened_brackets.first() {
+            // Every `]` after the outermost unclosed `[` closes a nested one.
+            let related = last_pair
+                .map(|(_, close_index)| close_index)
+                .filter(|&close_index| close_index > open_index);
+            return Err(BracketError {
+                program: self,
+                index: open_index,
+                related,
+            });
         }
 
-        Ok(())
+        Ok(ValidatedProgram {
+            program: self,
+            jumps,
+        })
+    }
+}
+
+/// Parse BF program from string, which never fails as symbols other than instructions are comments.
+impl FromStr for BrainFuckProgram {
+    type Err = Infallible;
+
+    fn from_str(content: &str) -> Result<BrainFuckProgram, Self::Err> {
+        Ok(Self::new(UNNAMED, content, Extensions::default()))
+    }
+}
+
+/// Provide structure to represent the bracket without matching partner found by
+/// [`BrainFuckProgram::validate_brackets`].
+#[derive(Debug)]
+pub struct BracketError {
+    /// BF program with unbalanced brackets.
+    program: BrainFuckProgram,
+    /// Index of the bracket without matching partner.
+    index: usize,
+    /// Index of the bracket related to it: the last `[` before unmatched `]`, which is already closed,
+    /// or the last `]` after unclosed `[`, which closes a nested one.
+    related: Option<usize>,
+}
+
+impl BracketError {
+    /// Get BF program with unbalanced brackets.
+    pub fn program(&self) -> &BrainFuckProgram {
+        &self.program
+    }
+
+    /// Check if the bracket without matching partner is `[`, otherwise it is `]`.
+    pub fn is_unclosed(&self) -> bool {
+        self.program.instructions[self.index].instruction == RawInstructions::ZeroJump
+    }
+
+    /// Get the source text of the bracket without matching partner.
+    pub fn span(&self) -> Span {
+        self.program.instructions[self.index].span()
+    }
+
+    /// Get the source text of the bracket related to the unmatched one, if there is such one.
+    pub fn related_span(&self) -> Option<Span> {
+        self.related
+            .map(|index| self.program.instructions[index].span())
+    }
+
+    /// Get diagnostic with the unmatched bracket and the related one.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let (message, label, related) = if self.is_unclosed() {
+            (
+                "no close bracket found matching bracket",
+                "unclosed bracket",
+                "the last `]` after it closes a nested `[`",
+            )
+        } else {
+            (
+                "no open bracket found matching bracket",
+                "unmatched bracket",
+                "the last `[` before it is already closed",
+            )
+        };
+        let diagnostic =
+            Diagnostic::error(message).with_primary(Label::from_span(self.span(), label));
+        match self.related_span() {
+            Some(span) => diagnostic.with_secondary(Label::from_span(span, related)),
+            None => diagnostic,
+        }
+    }
+
+    /// Render diagnostic with the source lines of the brackets, with ANSI colors if `color`.
+    pub fn render(&self, color: bool) -> String {
+        self.diagnostic()
+            .render(self.program.filename(), Some(self.program.source()), color)
+    }
+}
+
+/// Provide human-readable format of the unmatched bracket with its location.
+impl fmt::Display for BracketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = self.span();
+        write!(
+            f,
+            "Error in input file {}, {} at line {} column {}.",
+            self.program.filename.display(),
+            self.diagnostic().message(),
+            span.line,
+            span.column
+        )
+    }
+}
+
+impl Error for BracketError {}
+
+/// Provide structure to represent BF program with balanced brackets.
+///
+/// It can be created only by [`BrainFuckProgram::validate_brackets`], so holding it guarantees that every
+/// `[` has its matching `]`.
+#[derive(Debug)]
+pub struct ValidatedProgram {
+    /// BF program with balanced brackets.
+    program: BrainFuckProgram,
+    /// Index of the matching bracket for every bracket instruction, `None` for other instructions.
+    jumps: Vec<Option<usize>>,
+}
+
+impl ValidatedProgram {
+    /// Get validated BF program.
+    pub fn program(&self) -> &BrainFuckProgram {
+        &self.program
+    }
+
+    /// Get name of the file from where BF program is parsed.
+    pub fn filename(&self) -> &Path {
+        self.program.filename()
+    }
+
+    /// Get content of the file from where BF program is parsed.
+    pub fn source(&self) -> &[u8] {
+        self.program.source()
+    }
+
+    /// Get list of instructions for BF program.
+    pub fn instructions(&self) -> &[IntructionPosition] {
+        self.program.instructions()
+    }
+
+    /// Get index of the bracket matching the bracket at `index`. Return `None` if it is not a bracket.
+    pub fn matching_bracket(&self, index: usize) -> Option<usize> {
+        self.jumps.get(index).copied().flatten()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
 
-    use crate::BrainFuckProgram;
+    use crate::{BrainFuckProgram, Extensions, RawInstructions, Span, UNNAMED};
 
     #[test]
     fn test_new_bf() {
         let test_filename = PathBuf::from("testfilename");
         let test_content = "sometext\n><+-.,[]\ncomment <".to_string();
-        let bf_program = BrainFuckProgram::new(test_filename.as_path(), test_content);
+        let bf_program =
+            BrainFuckProgram::new(test_filename.as_path(), test_content, Extensions::default());
         assert_eq!(
             bf_program.filename(),
             test_filename,
@@ -233,22 +526,49 @@ mod tests {
     fn test_success_validate_brackets() {
         let test_filename = PathBuf::from("testfilename");
         let test_content = "sometext\n><+-.,[]\ncomment <".to_string();
-        let bf_program = BrainFuckProgram::new(test_filename.as_path(), test_content);
-        assert_eq!(
-            bf_program.validate_brackets(),
-            Ok(()),
+        let bf_program =
+            BrainFuckProgram::new(test_filename.as_path(), test_content, Extensions::default());
+        assert!(
+            bf_program.validate_brackets().is_ok(),
             "No errors during program parsing."
         )
     }
 
+    #[test]
+    fn test_validate_brackets_jump_table() {
+        let test_filename = PathBuf::from("testfilename");
+        let test_content = "+[>[-]<]\n[]".to_string();
+        let bf_program =
+            BrainFuckProgram::new(test_filename.as_path(), test_content, Extensions::default())
+                .validate_brackets()
+                .unwrap();
+        let expected_jumps: Vec<Option<usize>> = vec![
+            None,
+            Some(7),
+            None,
+            Some(5),
+            None,
+            Some(3),
+            None,
+            Some(1),
+            Some(9),
+            Some(8),
+        ];
+        let actual_jumps: Vec<Option<usize>> = (0..bf_program.instructions().len())
+            .map(|index| bf_program.matching_bracket(index))
+            .collect();
+        assert_eq!(expected_jumps, actual_jumps);
+    }
+
     #[test]
     fn test_error_validate_brackets() {
         let test_filename = PathBuf::from("testfilename");
         let test_content = "sometext\n><+-.,[[]\ncomment <".to_string();
-        let bf_program = BrainFuckProgram::new(test_filename.as_path(), test_content);
+        let bf_program =
+            BrainFuckProgram::new(test_filename.as_path(), test_content, Extensions::default());
         assert_eq!(
-            bf_program.validate_brackets(),
-            Err("Error in input file testfilename, no close bracket found matching bracket at line 2 column 7.".to_string()),
+            bf_program.validate_brackets().unwrap_err().to_string(),
+            "Error in input file testfilename, no close bracket found matching bracket at line 2 column 7.".to_string(),
             "Error during program parsing."
         )
     }
@@ -257,11 +577,175 @@ mod tests {
     fn test_error_validate_brackets_open_bracket_first() {
         let test_filename = PathBuf::from("testfilename");
         let test_content = "sometext\n><+-.,][\ncomment <".to_string();
-        let bf_program = BrainFuckProgram::new(test_filename.as_path(), test_content);
+        let bf_program =
+            BrainFuckProgram::new(test_filename.as_path(), test_content, Extensions::default());
         assert_eq!(
-            bf_program.validate_brackets(),
-            Err("Error in input file testfilename, no open bracket found matching bracket at line 2 column 7.".to_string()),
+            bf_program.validate_brackets().unwrap_err().to_string(),
+            "Error in input file testfilename, no open bracket found matching bracket at line 2 column 7.".to_string(),
             "Error during program parsing."
         )
     }
+
+    #[test]
+    fn test_debug_dump_extension() {
+        let test_filename = PathBuf::from("testfilename");
+        let test_content = "+#.".to_string();
+        let bf_program = BrainFuckProgram::new(
+            test_filename.as_path(),
+            test_content.clone(),
+            Extensions::default(),
+        );
+        assert_eq!(bf_program.instructions().len(), 2);
+
+        let extensions = Extensions { debug_dump: true };
+        let bf_program = BrainFuckProgram::new(test_filename.as_path(), test_content, extensions);
+        assert_eq!(bf_program.instructions().len(), 3);
+        assert_eq!(
+            bf_program.instructions()[1].instruction(),
+            &RawInstructions::DebugDump
+        );
+        assert_eq!(bf_program.instructions()[1].position(), 2);
+    }
+
+    #[test]
+    fn test_parse_from_string_bytes_and_reader() {
+        let content = "sometext\n><+-.,[]\ncomment <";
+        let locations = |program: &BrainFuckProgram| -> Vec<(usize, usize)> {
+            program
+                .instructions()
+                .iter()
+                .map(|instruction| (instruction.line(), instruction.position()))
+                .collect()
+        };
+        let expected = locations(&BrainFuckProgram::new(
+            "testfilename",
+            content,
+            Extensions::default(),
+        ));
+
+        let from_str = BrainFuckProgram::from_str(content).unwrap();
+        assert_eq!(from_str.filename(), Path::new(UNNAMED));
+        assert_eq!(locations(&from_str), expected);
+        let from_bytes = BrainFuckProgram::from_bytes(content.as_bytes());
+        assert_eq!(locations(&from_bytes), expected);
+        let from_reader = BrainFuckProgram::from_reader(content.as_bytes()).unwrap();
+        assert_eq!(locations(&from_reader), expected);
+
+        let extensions = Extensions { debug_dump: true };
+        let named = BrainFuckProgram::from_bytes_with_extensions(b"+#", extensions)
+            .with_filename("snippet");
+        assert_eq!(named.filename(), Path::new("snippet"));
+        assert_eq!(named.instructions().len(), 2);
+        assert_eq!(named.validate_brackets().unwrap().instructions().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_non_utf8_bytes() {
+        let content = b"caf\xe9 +\n\xc3\xa9+\xff\xfe[\r\n]";
+        let bf_program = BrainFuckProgram::from_bytes(content);
+        let actual: Vec<(&RawInstructions, usize, usize, usize)> = bf_program
+            .instructions()
+            .iter()
+            .map(|instruction| {
+                (
+                    instruction.instruction(),
+                    instruction.line(),
+                    instruction.position(),
+                    instruction.offset(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            actual,
+            vec![
+                (&RawInstructions::IncrementByte, 1, 6, 5),
+                (&RawInstructions::IncrementByte, 2, 2, 9),
+                (&RawInstructions::ZeroJump, 2, 5, 12),
+                (&RawInstructions::NonZeroJump, 3, 1, 15),
+            ]
+        );
+        for instruction in bf_program.instructions() {
+            assert!(b"+[]".contains(&content[instruction.offset()]));
+        }
+        assert!(bf_program.validate_brackets().is_ok());
+    }
+
+    #[test]
+    fn test_spans_with_crlf_and_tabs() {
+        let content = "+\t-\r\n\t>\r\n\r\n<\t\t]";
+        let bf_program = BrainFuckProgram::from_str(content).unwrap();
+        let file = bf_program.file();
+        let span = |start, line, column| Span {
+            file,
+            start,
+            length: 1,
+            line,
+            column,
+        };
+        let actual: Vec<Span> = bf_program
+            .instructions()
+            .iter()
+            .map(|instruction| instruction.span())
+            .collect();
+        assert_eq!(
+            actual,
+            vec![
+                span(0, 1, 1),
+                span(2, 1, 3),
+                span(6, 2, 2),
+                span(11, 4, 1),
+                span(14, 4, 4),
+            ]
+        );
+        for span in actual {
+            assert!("+->[]<".contains(&content[span.start..span.end()]));
+        }
+        assert_ne!(BrainFuckProgram::from_str(content).unwrap().file(), file);
+    }
+
+    #[test]
+    fn test_bracket_error_diagnostic() {
+        let bf_program = BrainFuckProgram::new("testfilename", "+[\n[-]\n>", Extensions::default());
+        let error = bf_program.validate_brackets().unwrap_err();
+        assert!(error.is_unclosed());
+        assert_eq!((error.span().line, error.span().column), (1, 2));
+        assert_eq!(
+            error.related_span().map(|span| (span.line, span.column)),
+            Some((2, 3))
+        );
+        assert_eq!(
+            error.render(false),
+            "error: no close bracket found matching bracket\n \
+             --> testfilename:1:2\n  \
+             |\n\
+             1 | +[\n  \
+             |  ^ unclosed bracket\n\
+             2 | [-]\n  \
+             |   - the last `]` after it closes a nested `[`\n"
+        );
+
+        let bf_program = BrainFuckProgram::new("testfilename", "[]]", Extensions::default());
+        let error = bf_program.validate_brackets().unwrap_err();
+        assert!(!error.is_unclosed());
+        let diagnostic = error.diagnostic();
+        assert_eq!(
+            diagnostic.message(),
+            "no open bracket found matching bracket"
+        );
+        assert_eq!(diagnostic.primary().map(|label| label.column()), Some(3));
+        assert_eq!(
+            diagnostic
+                .secondary()
+                .iter()
+                .map(|label| label.column())
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        let bf_program = BrainFuckProgram::new("testfilename", "][]", Extensions::default());
+        assert_eq!(
+            bf_program.validate_brackets().unwrap_err().related_span(),
+            None
+        );
+    }
 }
@@ -1,18 +1,332 @@
-use std::num::NonZeroUsize;
+use btf_interp::bytecode::CellWidth;
+use btf_interp::dump::DumpFormat;
+use btf_interp::ir::OptimizationLevel;
+use btf_interp::{CellEncoding, EofPolicy, OverflowPolicy};
+use std::env;
+use std::ffi::OsString;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I32,
+}
+
+impl FromStr for CellType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<CellType, Self::Err> {
+        match name {
+            "u8" => Ok(CellType::U8),
+            "u16" => Ok(CellType::U16),
+            "u32" => Ok(CellType::U32),
+            "u64" => Ok(CellType::U64),
+            "i8" => Ok(CellType::I8),
+            "i32" => Ok(CellType::I32),
+            _ => Err(format!(
+                "unknown cell type {}, expected one of: u8, u16, u32, u64, i8, i32",
+                name
+            )),
+        }
+    }
+}
+
+impl CellType {
+    /// Get the width of the cell type written into bytecode files.
+    pub fn width(self) -> CellWidth {
+        match self {
+            CellType::U8 => CellWidth::of::<u8>(),
+            CellType::U16 => CellWidth::of::<u16>(),
+            CellType::U32 => CellWidth::of::<u32>(),
+            CellType::U64 => CellWidth::of::<u64>(),
+            CellType::I8 => CellWidth::of::<i8>(),
+            CellType::I32 => CellWidth::of::<i32>(),
+        }
+    }
+
+    /// Get the cell type by the width written into bytecode files.
+    pub fn from_width(width: CellWidth) -> Option<CellType> {
+        [
+            CellType::U8,
+            CellType::U16,
+            CellType::U32,
+            CellType::U64,
+            CellType::I8,
+            CellType::I32,
+        ]
+        .into_iter()
+        .find(|cell_type| cell_type.width() == width)
+    }
+}
+
+/// Parse duration from the number of seconds, like `2` or `0.5`.
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    seconds
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid number of seconds {}", seconds))
+}
+
+/// Parse inclusive range from `FIRST-LAST`, like `10-20`, or from a single number.
+fn parse_range<T: FromStr + Copy>(range: &str) -> Result<RangeInclusive<T>, String> {
+    let invalid = || format!("invalid range {}, expected FIRST-LAST or a number", range);
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let first = first.trim().parse::<T>().map_err(|_| invalid())?;
+    let last = last.trim().parse::<T>().map_err(|_| invalid())?;
+    Ok(first..=last)
+}
+
+/// Names of subcommands and flags handled by `Args`, any other first argument is passed to `run`.
+const COMMANDS: [&str; 10] = [
+    "run",
+    "print",
+    "compile",
+    "debug",
+    "repl",
+    "help",
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+];
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "bft",
+    after_help = "Without subcommand the program is run, so `btf PROGRAM` is `btf run PROGRAM`."
+)]
+pub enum Args {
+    #[structopt(about = "Run BF program.")]
+    Run(RunArgs),
+
+    #[structopt(about = "Print parsed instructions of BF program.")]
+    Print(PrintArgs),
+
+    #[structopt(about = "Compile BF program into bytecode file.")]
+    Compile(CompileArgs),
+
+    #[structopt(about = "Debug BF program step by step with breakpoints.")]
+    Debug(DebugArgs),
+
+    #[structopt(about = "Run BF snippets typed line by line on one tape.")]
+    Repl(ReplArgs),
+}
+
+impl Args {
+    /// Parse the command line, where the first argument which is not a subcommand runs the program.
+    pub fn from_command_line() -> Args {
+        let mut args: Vec<OsString> = env::args_os().collect();
+        if args
+            .get(1)
+            .is_some_and(|first| !COMMANDS.iter().any(|command| first == command))
+        {
+            args.insert(1, OsString::from("run"));
+        }
+        Args::from_iter(args)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ParseArgs {
+    #[structopt(
+        long,
+        help = "Parse `#` into instruction writing the tape around the head when it is executed."
+    )]
+    pub debug_dump: bool,
+}
+
 #[derive(Debug, StructOpt)]
-#[structopt(name = "bft")]
-pub struct Args {
+pub struct VmArgs {
+    #[structopt(short, long, help = "The size of VM's tape.")]
+    pub cells: Option<NonZeroUsize>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Whether to extend VM's tape or not. By default - false."
+    )]
+    pub extensible: Option<bool>,
+
+    #[structopt(long, help = "The maximum size extensible VM's tape can grow to.")]
+    pub max_cells: Option<NonZeroUsize>,
+
+    #[structopt(
+        long,
+        help = "The maximum number of bytes cells of extensible VM's tape can occupy."
+    )]
+    pub max_tape_bytes: Option<NonZeroUsize>,
+
+    #[structopt(
+        long,
+        help = "The type of VM's cells: u8, u16, u32, u64, i8 or i32. By default - u8, or the type bytecode file is compiled for."
+    )]
+    pub cell_type: Option<CellType>,
+
+    #[structopt(
+        long,
+        default_value = "byte",
+        help = "How `,` and `.` map cell values to bytes: byte (lowest byte), utf8 (code point) or le (all bytes, little-endian)."
+    )]
+    pub encoding: CellEncoding,
+
+    #[structopt(
+        long,
+        default_value = "wrap",
+        help = "What `+` and `-` do past the bounds of the cell value: wrap, saturate or trap (stop with error)."
+    )]
+    pub overflow: OverflowPolicy,
+
+    #[structopt(
+        long,
+        default_value = "unchanged",
+        help = "What `,` does at the end of input: unchanged, zero, minus-one or error."
+    )]
+    pub eof: EofPolicy,
+
+    #[structopt(
+        long,
+        default_value = "text",
+        help = "The format of tapes dumped by `#` with --debug-dump: text or json (one object per line)."
+    )]
+    pub dump_format: DumpFormat,
+
+    #[structopt(
+        long,
+        default_value = "8",
+        help = "The number of cells dumped by `#` at each side of the head."
+    )]
+    pub dump_radius: usize,
+
+    #[structopt(
+        long,
+        help = "The file to write tapes dumped by `#` into. By default - stderr.",
+        parse(from_os_str)
+    )]
+    pub dump_file: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RunArgs {
     #[structopt(
         required(true),
         name = "PROGRAM",
-        help = "The file of BF program to be parsed.",
+        help = "The file of BF program to be parsed, or bytecode file if it has .bfc extension. `-` reads BF program from stdin, then `,` reads nothing.",
         parse(from_os_str)
     )]
     pub program: PathBuf,
 
+    #[structopt(flatten)]
+    pub parse: ParseArgs,
+
+    #[structopt(flatten)]
+    pub vm: VmArgs,
+
+    #[structopt(
+        long,
+        help = "The maximum number of BF instructions to execute before stopping with error. By default - unlimited."
+    )]
+    pub max_steps: Option<u64>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_seconds),
+        help = "The number of seconds to run BF program before stopping with error, like 2 or 0.5. By default - unlimited."
+    )]
+    pub timeout: Option<Duration>,
+
+    #[structopt(
+        long,
+        help = "The file to write executed instructions into, one JSON object per line.",
+        parse(from_os_str)
+    )]
+    pub trace: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_range),
+        help = "Trace only instructions at the lines of BF program, like 10-20."
+    )]
+    pub trace_lines: Option<RangeInclusive<usize>>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_range),
+        help = "Trace only instructions starting at the steps, counted from 0, like 1000-2000."
+    )]
+    pub trace_steps: Option<RangeInclusive<u64>>,
+
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Trace only every Nth of the selected instructions."
+    )]
+    pub trace_every: NonZeroU64,
+
+    #[structopt(
+        long,
+        help = "Print the hottest loops of BF program into stderr after it stops."
+    )]
+    pub profile: bool,
+
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "The number of the hottest loops printed by --profile."
+    )]
+    pub profile_top: usize,
+
+    #[structopt(
+        long,
+        help = "The file to write executions of every instruction and loop of BF program into, as JSON.",
+        parse(from_os_str)
+    )]
+    pub profile_json: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The file to write the state of VM into if BF program stops with error, e.g. on --max-steps or --timeout, to continue it with --resume.",
+        parse(from_os_str)
+    )]
+    pub snapshot: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The snapshot file written by --snapshot to continue BF program from. The tape and the options of VM are taken from it, --max-steps and --timeout apply to this run.",
+        parse(from_os_str)
+    )]
+    pub resume: Option<PathBuf>,
+
+    #[structopt(
+        short = "O",
+        long,
+        help = "The optimization level: 0 (none), 1 (fold runs of instructions), 2 (also replace loop idioms) or 3 (also address cells relative to the head). By default - 3, or 0 with --trace to trace every BF instruction."
+    )]
+    pub opt_level: Option<OptimizationLevel>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PrintArgs {
+    #[structopt(
+        required(true),
+        name = "PROGRAM",
+        help = "The file of BF program to be parsed, or bytecode file if it has .bfc extension. `-` reads BF program from stdin.",
+        parse(from_os_str)
+    )]
+    pub program: PathBuf,
+
+    #[structopt(flatten)]
+    pub parse: ParseArgs,
+
     #[structopt(short, long, help = "The size of VM's tape.")]
     pub cells: Option<NonZeroUsize>,
 
@@ -22,4 +336,111 @@ pub struct Args {
         help = "Whether to extend VM's tape or not. By default - false."
     )]
     pub extensible: Option<bool>,
+
+    #[structopt(
+        short = "O",
+        long,
+        help = "The optimization level: 0 (none), 1 (fold runs of instructions), 2 (also replace loop idioms) or 3 (also address cells relative to the head). By default - 0."
+    )]
+    pub opt_level: Option<OptimizationLevel>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CompileArgs {
+    #[structopt(
+        required(true),
+        name = "PROGRAM",
+        help = "The file of BF program to be parsed. `-` reads BF program from stdin.",
+        parse(from_os_str)
+    )]
+    pub program: PathBuf,
+
+    #[structopt(flatten)]
+    pub parse: ParseArgs,
+
+    #[structopt(
+        short,
+        long,
+        help = "The bytecode file to write. By default - PROGRAM with .bfc extension.",
+        parse(from_os_str)
+    )]
+    pub output: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        default_value = "u8",
+        help = "The type of VM's cells the program is compiled for: u8, u16, u32, u64, i8 or i32."
+    )]
+    pub cell_type: CellType,
+
+    #[structopt(
+        short = "O",
+        long,
+        default_value = "3",
+        help = "The optimization level: 0 (none), 1 (fold runs of instructions), 2 (also replace loop idioms) or 3 (also address cells relative to the head)."
+    )]
+    pub opt_level: OptimizationLevel,
+
+    #[structopt(
+        long,
+        help = "Don't write source locations, errors are reported at line 0 column 0."
+    )]
+    pub no_spans: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DebugArgs {
+    #[structopt(
+        required(true),
+        name = "PROGRAM",
+        help = "The file of BF program to be parsed, or bytecode file if it has .bfc extension.",
+        parse(from_os_str)
+    )]
+    pub program: PathBuf,
+
+    #[structopt(flatten)]
+    pub parse: ParseArgs,
+
+    #[structopt(flatten)]
+    pub vm: VmArgs,
+
+    #[structopt(
+        short,
+        long,
+        help = "The file `,` reads input of BF program from. By default - no input, as stdin reads debugger commands.",
+        parse(from_os_str)
+    )]
+    pub input: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        default_value = "100000",
+        help = "The number of the last executed instructions recorded to step back over, 0 to disable."
+    )]
+    pub history: usize,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ReplArgs {
+    #[structopt(flatten)]
+    pub parse: ParseArgs,
+
+    #[structopt(flatten)]
+    pub vm: VmArgs,
+
+    #[structopt(
+        short,
+        long,
+        help = "The file `,` reads input of snippets from. By default - no input, as stdin reads snippets.",
+        parse(from_os_str)
+    )]
+    pub input: Option<PathBuf>,
+
+    #[structopt(
+        short = "O",
+        long,
+        default_value = "3",
+        help = "The optimization level: 0 (none), 1 (fold runs of instructions), 2 (also replace loop idioms) or 3 (also address cells relative to the head)."
+    )]
+    pub opt_level: OptimizationLevel,
 }
@@ -0,0 +1,437 @@
+//! Provide interactive debugger executing BF program step by step.
+use btf_interp::ir::Op;
+use btf_interp::{CellKind, VirtualMachine};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Read, Write};
+
+/// Number of cells shown at each side of the head by `tape` command by default.
+const TAPE_RADIUS: usize = 5;
+
+/// Help shown by `help` command.
+const HELP: &str = "\
+Commands:
+  break LINE:COLUMN     Stop before the instruction at the location (b).
+  delete LINE:COLUMN    Remove the breakpoint at the location (d).
+  breakpoints           List breakpoints.
+  step [COUNT]          Execute COUNT instructions, one by default (s).
+  next                  Run the current loop to completion (n).
+  continue              Run until a breakpoint or the end of the program (c).
+  step-back [COUNT]     Revert COUNT instructions, one by default (sb).
+  reverse-continue      Revert instructions until a breakpoint or the start of the history (rc).
+  last-write [INDEX]    Show when the cell at INDEX, or the current cell, was last written (lw).
+  tape [RADIUS]         Show cells around the head (t).
+  set [INDEX] VALUE     Set the cell at INDEX, or the current cell, to VALUE.
+  where                 Show the instruction to be executed next (w).
+  help                  Show this help (h).
+  quit                  Stop debugging (q).";
+
+/// Provide structure of debugger executing BF program by Virtual Machine.
+pub struct Debugger<'a, T> {
+    /// Virtual Machine executing the program.
+    vm: VirtualMachine<'a, T>,
+    /// Indices of BF instructions of the source program to stop before.
+    breakpoints: BTreeSet<usize>,
+    /// Whether the program is stopped with error and can't be executed further.
+    failed: bool,
+}
+
+impl<'a, T: CellKind> Debugger<'a, T> {
+    /// Create debugger for the program of Virtual Machine.
+    pub fn new(vm: VirtualMachine<'a, T>) -> Self {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            failed: false,
+        }
+    }
+
+    /// Execute commands read from `commands` until `quit` or their end, reading `,` of the program
+    /// from `input` and writing `.` of the program and replies into `output`.
+    pub fn debug(
+        &mut self,
+        input: &mut impl Read,
+        commands: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        self.show_location(output)?;
+        let mut line = String::new();
+        loop {
+            write!(output, "(btf) ")?;
+            output.flush()?;
+            line.clear();
+            if commands.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((&command, arguments)) = words.split_first() else {
+                continue;
+            };
+            match command {
+                "break" | "b" => self.add_breakpoint(arguments, output)?,
+                "delete" | "d" => self.delete_breakpoint(arguments, output)?,
+                "breakpoints" => self.show_breakpoints(output)?,
+                "step" | "s" => match arguments.first().map(|count| count.parse::<u64>()) {
+                    None => self.resume(input, output, |_| true)?,
+                    Some(Ok(count)) if count > 0 => {
+                        let mut remaining = count;
+                        self.resume(input, output, |_| {
+                            remaining -= 1;
+                            remaining == 0
+                        })?
+                    }
+                    Some(_) => writeln!(output, "Expected positive number of steps.")?,
+                },
+                "next" | "n" => self.next(input, output)?,
+                "continue" | "c" => self.resume(input, output, |_| false)?,
+                "step-back" | "sb" => match arguments.first().map(|count| count.parse::<u64>()) {
+                    None => self.reverse(output, |_| true)?,
+                    Some(Ok(count)) if count > 0 => {
+                        let mut remaining = count;
+                        self.reverse(output, |_| {
+                            remaining -= 1;
+                            remaining == 0
+                        })?
+                    }
+                    Some(_) => writeln!(output, "Expected positive number of steps.")?,
+                },
+                "reverse-continue" | "rc" => self.reverse(output, |_| false)?,
+                "last-write" | "lw" => self.show_last_write(arguments, output)?,
+                "tape" | "t" => match arguments.first().map(|radius| radius.parse::<usize>()) {
+                    None => self.show_tape(TAPE_RADIUS, output)?,
+                    Some(Ok(radius)) => self.show_tape(radius, output)?,
+                    Some(Err(_)) => writeln!(output, "Expected number of cells.")?,
+                },
+                "set" => self.set_cell(arguments, output)?,
+                "where" | "w" => self.show_location(output)?,
+                "help" | "h" => writeln!(output, "{}", HELP)?,
+                "quit" | "q" => return Ok(()),
+                _ => writeln!(
+                    output,
+                    "Unknown command {}, type help for commands.",
+                    command
+                )?,
+            }
+        }
+    }
+
+    /// Execute operations until `stop` returns `true` after an operation, the program reaches a
+    /// breakpoint, finishes or fails.
+    fn resume(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        mut stop: impl FnMut(&VirtualMachine<'a, T>) -> bool,
+    ) -> io::Result<()> {
+        if self.failed {
+            return writeln!(output, "The program is stopped with error.");
+        }
+        if self.vm.is_finished() {
+            return writeln!(output, "The program is finished.");
+        }
+        loop {
+            if let Err(err) = self.vm.step(input, output) {
+                self.failed = true;
+                let diagnostic = err.render(self.vm.program(), false);
+                return write!(output, "\nThe program is stopped with {}", diagnostic);
+            }
+            if self.vm.is_finished() || stop(&self.vm) || self.at_breakpoint() {
+                break;
+            }
+        }
+        self.show_location(output)
+    }
+
+    /// Revert operations until `stop` returns `true` after an operation, the program reaches a
+    /// breakpoint or the start of the history.
+    fn reverse(
+        &mut self,
+        output: &mut impl Write,
+        mut stop: impl FnMut(&VirtualMachine<'a, T>) -> bool,
+    ) -> io::Result<()> {
+        if self.vm.history_len() == 0 {
+            return writeln!(
+                output,
+                "There are no instructions to revert in the history."
+            );
+        }
+        while self.vm.step_back() {
+            // The failed operation is reverted, so the program can be executed again.
+            self.failed = false;
+            if stop(&self.vm) || self.at_breakpoint() {
+                break;
+            }
+        }
+        self.show_location(output)
+    }
+
+    /// Execute operations until the instruction pointer leaves the innermost loop around it, or the
+    /// loop starting at it. Execute one operation if it is not in a loop.
+    fn next(&mut self, input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+        let instructions = self.vm.program().instructions();
+        let pointer = self.vm.instruction_pointer();
+        let current_loop = instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(open, instruction)| match *instruction.op() {
+                Op::JumpIfZero(close) if open <= pointer && pointer <= close => Some((open, close)),
+                _ => None,
+            })
+            .next_back();
+        match current_loop {
+            Some((open, close)) => self.resume(input, output, |vm| {
+                let pointer = vm.instruction_pointer();
+                pointer < open || pointer > close
+            }),
+            None => self.resume(input, output, |_| true),
+        }
+    }
+
+    /// Check if the operation to be executed next is built from BF instruction with breakpoint.
+    fn at_breakpoint(&self) -> bool {
+        self.vm
+            .program()
+            .instructions()
+            .get(self.vm.instruction_pointer())
+            .is_some_and(|instruction| {
+                self.breakpoints
+                    .range(instruction.source())
+                    .next()
+                    .is_some()
+            })
+    }
+
+    /// Find index of BF instruction of the source program by its location `LINE:COLUMN`.
+    fn find_instruction(&self, location: Option<&&str>) -> Result<usize, String> {
+        let location = location.ok_or("Expected location LINE:COLUMN.")?;
+        let (line, position) = location
+            .split_once(':')
+            .and_then(|(line, position)| Some((line.parse().ok()?, position.parse().ok()?)))
+            .ok_or_else(|| format!("Invalid location {}, expected LINE:COLUMN.", location))?;
+        self.vm
+            .program()
+            .locations()
+            .iter()
+            .position(|instruction| instruction.line == line && instruction.position == position)
+            .ok_or_else(|| format!("There is no instruction at {}.", location))
+    }
+
+    /// Add breakpoint at the location given in the arguments.
+    fn add_breakpoint(&mut self, arguments: &[&str], output: &mut impl Write) -> io::Result<()> {
+        match self.find_instruction(arguments.first()) {
+            Ok(index) => {
+                self.breakpoints.insert(index);
+                writeln!(output, "Breakpoint at {}.", arguments[0])
+            }
+            Err(message) => writeln!(output, "{}", message),
+        }
+    }
+
+    /// Remove breakpoint at the location given in the arguments.
+    fn delete_breakpoint(&mut self, arguments: &[&str], output: &mut impl Write) -> io::Result<()> {
+        match self.find_instruction(arguments.first()) {
+            Ok(index) if self.breakpoints.remove(&index) => {
+                writeln!(output, "Deleted breakpoint at {}.", arguments[0])
+            }
+            Ok(_) => writeln!(output, "There is no breakpoint at {}.", arguments[0]),
+            Err(message) => writeln!(output, "{}", message),
+        }
+    }
+
+    /// Show locations of all breakpoints.
+    fn show_breakpoints(&self, output: &mut impl Write) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            return writeln!(output, "No breakpoints.");
+        }
+        for &index in &self.breakpoints {
+            let location = self.vm.program().location(index);
+            writeln!(
+                output,
+                "Breakpoint at {}:{}.",
+                location.line, location.position
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Show cells from `radius` cells left to `radius` cells right of the head, marking the head.
+    fn show_tape(&self, radius: usize, output: &mut impl Write) -> io::Result<()> {
+        let head = self.vm.head();
+        let last = head.saturating_add(radius).min(self.vm.tape_size() - 1);
+        for index in head.saturating_sub(radius)..=last {
+            let marker = if index == head { '>' } else { ' ' };
+            if let Some(value) = self.vm.cell(index) {
+                writeln!(output, "{} {:>8}: {}", marker, index, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the cell given in the arguments, or the current one, to the value.
+    fn set_cell(&mut self, arguments: &[&str], output: &mut impl Write) -> io::Result<()> {
+        let (index, value) = match *arguments {
+            [value] => (Ok(self.vm.head()), value),
+            [index, value] => (index.parse::<usize>(), value),
+            _ => return writeln!(output, "Expected [INDEX] VALUE."),
+        };
+        let (Ok(index), Ok(value)) = (index, value.parse::<i128>()) else {
+            return writeln!(output, "Expected [INDEX] VALUE as numbers.");
+        };
+        // The value is truncated to the width of the cell as two's complement bits.
+        if !self.vm.set_cell(index, T::value_from_bits(value as u64)) {
+            return writeln!(output, "Cell {} is out of the tape.", index);
+        }
+        let value = self.vm.cell(index).unwrap_or_default();
+        writeln!(output, "Cell {} is {}.", index, value)
+    }
+
+    /// Show the latest write of the cell given in the arguments, or the current one, in the history.
+    fn show_last_write(&self, arguments: &[&str], output: &mut impl Write) -> io::Result<()> {
+        let index = match arguments.first().map(|index| index.parse::<usize>()) {
+            None => self.vm.head(),
+            Some(Ok(index)) => index,
+            Some(Err(_)) => return writeln!(output, "Expected cell index."),
+        };
+        if self.vm.cell(index).is_none() {
+            return writeln!(output, "Cell {} is out of the tape.", index);
+        }
+        let Some(write) = self.vm.last_write(index) else {
+            return writeln!(output, "Cell {} isn't written in the history.", index);
+        };
+        let program = self.vm.program();
+        let instruction = &program.instructions()[write.instruction_pointer];
+        let location = program.location(instruction.source().start);
+        writeln!(
+            output,
+            "Cell {} was last written at step {} by [{}:{}:{}] {}: {} -> {}.",
+            index,
+            write.step,
+            program.filename().display(),
+            location.line,
+            location.position,
+            instruction.op(),
+            write.before,
+            write.after
+        )
+    }
+
+    /// Show location of the operation to be executed next, the head and the current cell.
+    fn show_location(&self, output: &mut impl Write) -> io::Result<()> {
+        let program = self.vm.program();
+        let Some(instruction) = program.instructions().get(self.vm.instruction_pointer()) else {
+            return writeln!(
+                output,
+                "The program is finished after {} steps.",
+                self.vm.steps()
+            );
+        };
+        let location = program.location(instruction.source().start);
+        let head = self.vm.head();
+        writeln!(
+            output,
+            "[{}:{}:{}] {} (head {}, cell value {})",
+            program.filename().display(),
+            location.line,
+            location.position,
+            instruction.op(),
+            head,
+            self.vm.cell(head).unwrap_or_default()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debugger::Debugger;
+    use btf_interp::ir::{OptimizationLevel, Program};
+    use btf_interp::VirtualMachine;
+    use btf_types::BrainFuckProgram;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{env, fs, process};
+
+    /// Compile the program without optimizations, so every operation is one BF instruction.
+    fn compile(content: &str) -> Program {
+        let path = program_path();
+        fs::write(&path, content).unwrap();
+        let program = BrainFuckProgram::from_file(&path)
+            .unwrap()
+            .validate_brackets()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        Program::compile(&program, OptimizationLevel::None)
+    }
+
+    /// Get the path of a new temporary file of the program.
+    fn program_path() -> PathBuf {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let index = FILES.fetch_add(1, Ordering::Relaxed);
+        env::temp_dir().join(format!("btf-debugger-{}-{}.bf", process::id(), index))
+    }
+
+    /// Execute debugger commands, returning the debugger and its replies.
+    fn debug<'a>(program: &'a Program, commands: &str) -> (Debugger<'a, u8>, String) {
+        let vm: VirtualMachine<u8> = VirtualMachine::new(program, None, None);
+        let mut debugger = Debugger::new(vm);
+        let mut input: &[u8] = &[];
+        let mut output = Vec::new();
+        debugger
+            .debug(&mut input, &mut commands.as_bytes(), &mut output)
+            .unwrap();
+        (debugger, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_next_runs_loop_to_completion() {
+        let program = compile("+++[>+<-]>.");
+        let (debugger, output) = debug(&program, "step 5\nnext\n");
+        assert_eq!(debugger.vm.instruction_pointer(), 9);
+        assert_eq!(debugger.vm.cell(0), Some(0));
+        assert_eq!(debugger.vm.cell(1), Some(3));
+        let filename = program.filename().display();
+        assert_eq!(
+            output,
+            format!(
+                "[{0}:1:1] Add 1 (head 0, cell value 0)\n\
+                 (btf) [{0}:1:6] Add 1 (head 1, cell value 0)\n\
+                 (btf) [{0}:1:10] Move 1 (head 0, cell value 0)\n\
+                 (btf) \n",
+                filename
+            )
+        );
+
+        // Out of loops `next` executes one operation.
+        let (debugger, _) = debug(&program, "next\nnext\n");
+        assert_eq!(debugger.vm.instruction_pointer(), 2);
+
+        // At the loop start `next` runs the whole loop.
+        let (debugger, _) = debug(&program, "step 3\nnext\n");
+        assert_eq!(debugger.vm.instruction_pointer(), 9);
+        assert_eq!(debugger.vm.cell(1), Some(3));
+    }
+
+    #[test]
+    fn test_breakpoints_stop_execution() {
+        let program = compile("+++[>+<-]>.");
+        let (debugger, output) = debug(&program, "break 1:6\ncontinue\n");
+        assert_eq!(debugger.vm.instruction_pointer(), 5);
+        assert_eq!(debugger.vm.cell(1), Some(0));
+        assert!(output.contains("Breakpoint at 1:6.\n"));
+
+        let (debugger, _) = debug(&program, "b 1:6\nc\nc\nc\n");
+        assert_eq!(debugger.vm.instruction_pointer(), 5);
+        assert_eq!(debugger.vm.cell(1), Some(2));
+
+        let (debugger, output) = debug(
+            &program,
+            "break 1:6\ncontinue\ndelete 1:6\nbreakpoints\ncontinue\n",
+        );
+        assert!(debugger.vm.is_finished());
+        assert_eq!(debugger.vm.cell(1), Some(3));
+        assert!(output.contains("Deleted breakpoint at 1:6.\n(btf) No breakpoints.\n"));
+        assert!(output.ends_with("\u{3}The program is finished after 21 steps.\n(btf) \n"));
+
+        let (_, output) = debug(&program, "break 2:1\n");
+        assert!(output.contains("There is no instruction at 2:1.\n"));
+    }
+}
@@ -1,34 +1,316 @@
 //! Provide implementation of parsing BF program.
 mod cli;
-use btf_interp::VirtualMachine;
-use btf_types::BrainFuckProgram;
-use cli::Args;
+mod debugger;
+mod repl;
+use btf_interp::bytecode::{self, CellWidth};
+use btf_interp::dump::TapeDump;
+use btf_interp::ir::{OptimizationLevel, Program};
+use btf_interp::snapshot::Snapshot;
+use btf_interp::trace::{TraceFilter, Tracer};
+use btf_interp::{CellKind, VirtualMachine};
+use btf_types::diagnostic::use_color;
+use btf_types::{BrainFuckProgram, Extensions, ValidatedProgram};
+use cli::{
+    Args, CellType, CompileArgs, DebugArgs, ParseArgs, PrintArgs, ReplArgs, RunArgs, VmArgs,
+};
+use debugger::Debugger;
+use repl::Repl;
 use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
 use std::process::{exit, ExitCode};
-use structopt::StructOpt;
-
-fn run_bft(args: Args) -> Result<(), Box<dyn Error>> {
-    let bf_program = BrainFuckProgram::from_file(args.program);
-    match bf_program {
-        Ok(bf_program) => {
-            bf_program.validate_brackets()?;
-            let vm: VirtualMachine<u8> =
-                VirtualMachine::new(&bf_program, args.cells, args.extensible);
-            vm.interpreter();
+use std::time::Instant;
+
+/// Provide error already rendered as diagnostic with the source lines, shown without `btf:` prefix.
+#[derive(Debug)]
+struct Diagnosed(String);
+
+impl fmt::Display for Diagnosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for Diagnosed {}
+
+/// Get the extensions of BF language enabled in the command line.
+fn extensions(args: &ParseArgs) -> Extensions {
+    Extensions {
+        debug_dump: args.debug_dump,
+    }
+}
+
+/// Check if the program is read from stdin, which is given as `-`.
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Parse BF program from the file, or from stdin if the path is `-`, with the extensions.
+fn parse_program(path: &Path, parse: &ParseArgs) -> Result<ValidatedProgram, Box<dyn Error>> {
+    let bf_program = if is_stdin(path) {
+        BrainFuckProgram::from_reader_with_extensions(io::stdin().lock(), extensions(parse))?
+            .with_filename("<stdin>")
+    } else {
+        BrainFuckProgram::from_file_with_extensions(path, extensions(parse))?
+    };
+    bf_program
+        .validate_brackets()
+        .map_err(|err| Diagnosed(err.render(use_color())).into())
+}
+
+/// Load bytecode file if it has `.bfc` extension with the cell type it is compiled for, otherwise
+/// parse BF program with the extensions and compile it with the optimization level, `default_level`
+/// if it isn't given. Parse and optimization options are rejected for bytecode file, which is
+/// already compiled.
+fn load_program(
+    path: &Path,
+    parse: &ParseArgs,
+    level: Option<OptimizationLevel>,
+    default_level: OptimizationLevel,
+) -> Result<(Program, Option<CellWidth>), Box<dyn Error>> {
+    if path.extension().is_some_and(|extension| extension == "bfc") {
+        if level.is_some() || parse.debug_dump {
+            return Err(
+                "--opt-level and --debug-dump can't be used with bytecode file, \
+                 it is compiled with them"
+                    .into(),
+            );
         }
-        Err(e) => {
-            eprintln!("{}", e);
+        let (header, bf_program) = bytecode::read(&mut File::open(path)?)?;
+        return Ok((bf_program, Some(header.cell)));
+    }
+    let bf_program = parse_program(path, parse)?;
+    Ok((
+        Program::compile(&bf_program, level.unwrap_or(default_level)),
+        None,
+    ))
+}
+
+fn run_bft(args: RunArgs) -> Result<(), Box<dyn Error>> {
+    let default_level = if args.trace.is_some() {
+        OptimizationLevel::None
+    } else {
+        OptimizationLevel::Offsets
+    };
+    let (bf_program, compiled_cell) =
+        load_program(&args.program, &args.parse, args.opt_level, default_level)?;
+    let snapshot = match &args.resume {
+        Some(path) => Some(Snapshot::read(&mut BufReader::new(File::open(path)?))?),
+        None => None,
+    };
+    // The snapshot is restored into VM with the cell type it is taken for.
+    let compiled_cell = snapshot.as_ref().map(Snapshot::cell).or(compiled_cell);
+    let snapshot = snapshot.as_ref();
+    match cell_type(compiled_cell, args.vm.cell_type)? {
+        CellType::U8 => run_vm::<u8>(&args, &bf_program, snapshot),
+        CellType::U16 => run_vm::<u16>(&args, &bf_program, snapshot),
+        CellType::U32 => run_vm::<u32>(&args, &bf_program, snapshot),
+        CellType::U64 => run_vm::<u64>(&args, &bf_program, snapshot),
+        CellType::I8 => run_vm::<i8>(&args, &bf_program, snapshot),
+        CellType::I32 => run_vm::<i32>(&args, &bf_program, snapshot),
+    }
+}
+
+/// Get the cell type given in the command line, or the one bytecode file is compiled for.
+fn cell_type(
+    compiled_cell: Option<CellWidth>,
+    cell_type: Option<CellType>,
+) -> Result<CellType, Box<dyn Error>> {
+    let Some(width) = compiled_cell else {
+        return Ok(cell_type.unwrap_or(CellType::U8));
+    };
+    let compiled_type = CellType::from_width(width)
+        .ok_or_else(|| format!("unsupported cell type {} of bytecode file", width))?;
+    if cell_type.is_some_and(|cell_type| cell_type != compiled_type) {
+        return Err(format!("bytecode file is compiled for {} cells", width).into());
+    }
+    Ok(compiled_type)
+}
+
+/// Create VM with the tape, the cell and the tape dump options given in the command line.
+fn new_vm<'a, T: CellKind>(
+    args: &VmArgs,
+    bf_program: &'a Program,
+) -> Result<VirtualMachine<'a, T>, Box<dyn Error>> {
+    let dump = match &args.dump_file {
+        Some(path) => TapeDump::new(File::create(path)?, args.dump_format, args.dump_radius),
+        None => TapeDump::new(io::stderr(), args.dump_format, args.dump_radius),
+    };
+    let vm = VirtualMachine::new(bf_program, args.cells, args.extensible)
+        .with_max_tape_size(args.max_cells)
+        .with_max_tape_bytes(args.max_tape_bytes)
+        .with_cell_encoding(args.encoding)
+        .with_overflow_policy(args.overflow)
+        .with_eof_policy(args.eof)
+        .with_tape_dump(dump);
+    if let Some(max_tape_size) = vm.max_tape_size().filter(|&limit| limit < vm.tape_size()) {
+        return Err(format!(
+            "tape of {} cells is larger than its limit of {} cells, set smaller --cells",
+            vm.tape_size(),
+            max_tape_size
+        )
+        .into());
+    }
+    Ok(vm)
+}
+
+fn run_vm<T: CellKind>(
+    args: &RunArgs,
+    bf_program: &Program,
+    snapshot: Option<&Snapshot>,
+) -> Result<(), Box<dyn Error>> {
+    let tracer = match &args.trace {
+        Some(path) => Some(Tracer::new(
+            BufWriter::new(File::create(path)?),
+            TraceFilter {
+                lines: args.trace_lines.clone(),
+                steps: args.trace_steps.clone(),
+                every: args.trace_every,
+            },
+        )),
+        None => None,
+    };
+    let mut vm: VirtualMachine<T> = new_vm(&args.vm, bf_program)?
+        .with_tracer(tracer)
+        .with_profiling(args.profile || args.profile_json.is_some());
+    if let Some(snapshot) = snapshot {
+        vm.restore(snapshot)?;
+    }
+    // The limits of this run replace ones of the run the snapshot is taken from.
+    let mut vm = vm
+        .with_max_steps(args.max_steps)
+        .with_deadline(args.timeout.map(|timeout| Instant::now() + timeout));
+    let result = vm.run(&mut io::stdin().lock(), &mut io::stdout().lock());
+    if let (Err(_), Some(path)) = (&result, &args.snapshot) {
+        let mut writer = BufWriter::new(File::create(path)?);
+        vm.snapshot().write(&mut writer)?;
+        writer.flush()?;
+    }
+    if let Some(mut tracer) = vm.take_tracer() {
+        tracer.flush()?;
+    }
+    if let Some(profile) = vm.profile() {
+        if args.profile {
+            profile.write_table(&mut io::stderr().lock(), args.profile_top)?;
         }
+        if let Some(path) = &args.profile_json {
+            let mut writer = BufWriter::new(File::create(path)?);
+            profile.write_json(&mut writer)?;
+            writer.flush()?;
+        }
+    }
+    result.map_err(|err| Diagnosed(err.render(bf_program, use_color())))?;
+    Ok(())
+}
+
+fn print_bft(args: PrintArgs) -> Result<(), Box<dyn Error>> {
+    let (bf_program, _) = load_program(
+        &args.program,
+        &args.parse,
+        args.opt_level,
+        OptimizationLevel::None,
+    )?;
+    let vm: VirtualMachine<u8> = VirtualMachine::new(&bf_program, args.cells, args.extensible);
+    vm.interpreter();
+    Ok(())
+}
+
+fn debug_bft(args: DebugArgs) -> Result<(), Box<dyn Error>> {
+    if is_stdin(&args.program) {
+        return Err("BF program can't be read from stdin, as stdin reads debugger commands".into());
+    }
+    let (bf_program, compiled_cell) =
+        load_program(&args.program, &args.parse, None, OptimizationLevel::None)?;
+    match cell_type(compiled_cell, args.vm.cell_type)? {
+        CellType::U8 => debug_vm::<u8>(&args, &bf_program),
+        CellType::U16 => debug_vm::<u16>(&args, &bf_program),
+        CellType::U32 => debug_vm::<u32>(&args, &bf_program),
+        CellType::U64 => debug_vm::<u64>(&args, &bf_program),
+        CellType::I8 => debug_vm::<i8>(&args, &bf_program),
+        CellType::I32 => debug_vm::<i32>(&args, &bf_program),
+    }
+}
+
+fn debug_vm<T: CellKind>(args: &DebugArgs, bf_program: &Program) -> Result<(), Box<dyn Error>> {
+    let mut input: Box<dyn Read> = match &args.input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::empty()),
+    };
+    let vm: VirtualMachine<T> = new_vm(&args.vm, bf_program)?.with_history(args.history);
+    let mut debugger = Debugger::new(vm);
+    debugger.debug(
+        &mut input,
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+    )?;
+    Ok(())
+}
+
+fn repl_bft(args: ReplArgs) -> Result<(), Box<dyn Error>> {
+    let empty = BrainFuckProgram::new("<repl>", "", extensions(&args.parse));
+    let empty = Program::compile(&empty.validate_brackets()?, args.opt_level);
+    match cell_type(None, args.vm.cell_type)? {
+        CellType::U8 => repl_vm::<u8>(&args, &empty),
+        CellType::U16 => repl_vm::<u16>(&args, &empty),
+        CellType::U32 => repl_vm::<u32>(&args, &empty),
+        CellType::U64 => repl_vm::<u64>(&args, &empty),
+        CellType::I8 => repl_vm::<i8>(&args, &empty),
+        CellType::I32 => repl_vm::<i32>(&args, &empty),
+    }
+}
+
+fn repl_vm<T: CellKind>(args: &ReplArgs, empty: &Program) -> Result<(), Box<dyn Error>> {
+    let mut input: Box<dyn Read> = match &args.input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::empty()),
+    };
+    let vm: VirtualMachine<T> = new_vm(&args.vm, empty)?;
+    let mut repl = Repl::new(vm, extensions(&args.parse), args.opt_level);
+    repl.repl(
+        &mut input,
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+    )?;
+    Ok(())
+}
+
+fn compile_bft(args: CompileArgs) -> Result<(), Box<dyn Error>> {
+    if is_stdin(&args.program) && args.output.is_none() {
+        return Err("--output is required to compile BF program read from stdin".into());
     }
+    let bf_program = parse_program(&args.program, &args.parse)?;
+    let bf_program = Program::compile(&bf_program, args.opt_level);
+    let output = args
+        .output
+        .unwrap_or_else(|| args.program.with_extension("bfc"));
+    let mut writer = BufWriter::new(File::create(output)?);
+    bytecode::write(
+        &bf_program,
+        args.cell_type.width(),
+        !args.no_spans,
+        &mut writer,
+    )?;
+    writer.flush()?;
     Ok(())
 }
 
 fn main() -> ExitCode {
-    let args = cli::Args::from_args();
-    match run_bft(args) {
+    let result = match Args::from_command_line() {
+        Args::Run(args) => run_bft(args),
+        Args::Print(args) => print_bft(args),
+        Args::Compile(args) => compile_bft(args),
+        Args::Debug(args) => debug_bft(args),
+        Args::Repl(args) => repl_bft(args),
+    };
+    match result {
         Ok(_smth) => exit(0),
         Err(e) => {
-            eprintln!("btf: {}", e);
+            match e.downcast_ref::<Diagnosed>() {
+                Some(diagnosed) => eprint!("{}", diagnosed),
+                None => eprintln!("btf: {}", e),
+            }
             exit(1);
         }
     }
@@ -0,0 +1,309 @@
+//! Provide interactive loop executing BF snippets line by line on one tape.
+use btf_interp::ir::{OptimizationLevel, Program};
+use btf_interp::{CellKind, VirtualMachine};
+use btf_types::{BrainFuckProgram, Extensions, RawInstructions, ValidatedProgram};
+use std::io::{self, BufRead, Read, Write};
+
+/// Number of cells shown at each side of the head after every snippet.
+const TAPE_RADIUS: usize = 8;
+
+/// Help shown by `:help` command.
+const HELP: &str = "\
+Type BF instructions to run them on the tape. A line with unclosed `[` continues on the next lines.
+Commands:
+  :reset                Set all cells to zero and move the head to the first cell.
+  :load FILE            Run BF program from the file on the tape.
+  :dump                 Show the head, the step count and all non-zero cells.
+  :help                 Show this help.
+  :quit                 Stop the REPL.";
+
+/// Provide structure of REPL running snippets of BF program on the tape of Virtual Machine.
+pub struct Repl<'e, T> {
+    /// Virtual Machine holding the tape, with the empty program between snippets.
+    vm: Option<VirtualMachine<'e, T>>,
+    /// Extension instructions enabled for snippets and loaded files.
+    extensions: Extensions,
+    /// The optimization level snippets are compiled with.
+    level: OptimizationLevel,
+    /// Lines of the snippet with unclosed brackets.
+    pending: String,
+}
+
+impl<'e, T: CellKind> Repl<'e, T> {
+    /// Create REPL running snippets on the tape of Virtual Machine created for the empty program.
+    pub fn new(
+        vm: VirtualMachine<'e, T>,
+        extensions: Extensions,
+        level: OptimizationLevel,
+    ) -> Self {
+        Repl {
+            vm: Some(vm),
+            extensions,
+            level,
+            pending: String::new(),
+        }
+    }
+
+    /// Execute snippets and commands read from `lines` until `:quit` or their end, reading `,` of
+    /// snippets from `input` and writing `.` of snippets and replies into `output`.
+    pub fn repl(
+        &mut self,
+        input: &mut impl Read,
+        lines: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            let prompt = if self.pending.is_empty() {
+                "bf> "
+            } else {
+                "... "
+            };
+            write!(output, "{}", prompt)?;
+            output.flush()?;
+            line.clear();
+            if lines.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            let command = line.trim();
+            if self.pending.is_empty() && command.starts_with(':') {
+                let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+                match name {
+                    ":reset" => {
+                        self.vm_mut().reset();
+                        self.show_tape(output)?;
+                    }
+                    ":load" => self.load(argument.trim(), input, output)?,
+                    ":dump" => self.dump(output)?,
+                    ":help" => writeln!(output, "{}", HELP)?,
+                    ":quit" => return Ok(()),
+                    _ => writeln!(output, "Unknown command {}, type :help for commands.", name)?,
+                }
+                continue;
+            }
+
+            self.pending.push_str(&line);
+            let snippet = BrainFuckProgram::new("<repl>", &self.pending, self.extensions);
+            if has_unclosed_brackets(&snippet) {
+                continue;
+            }
+            self.pending.clear();
+            match snippet.validate_brackets() {
+                Ok(snippet) => self.execute(&snippet, input, output)?,
+                Err(err) => write!(output, "{}", err.render(false))?,
+            }
+        }
+    }
+
+    /// Get Virtual Machine holding the tape.
+    fn vm(&self) -> &VirtualMachine<'e, T> {
+        self.vm
+            .as_ref()
+            .expect("VM is returned after every snippet")
+    }
+
+    /// Get Virtual Machine holding the tape to change it.
+    fn vm_mut(&mut self) -> &mut VirtualMachine<'e, T> {
+        self.vm
+            .as_mut()
+            .expect("VM is returned after every snippet")
+    }
+
+    /// Run BF program from the file given in the argument on the tape.
+    fn load(
+        &mut self,
+        path: &str,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        if path.is_empty() {
+            return writeln!(output, "Expected file name.");
+        }
+        let program = BrainFuckProgram::from_file_with_extensions(path, self.extensions)
+            .map_err(|err| format!("{}\n", err))
+            .and_then(|program| program.validate_brackets().map_err(|err| err.render(false)));
+        match program {
+            Ok(program) => self.execute(&program, input, output),
+            Err(message) => write!(output, "{}", message),
+        }
+    }
+
+    /// Run the program on the tape, then show its output and the tape around the head.
+    fn execute(
+        &mut self,
+        program: &ValidatedProgram,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let program = Program::compile(program, self.level);
+        let vm = self.vm.take().expect("VM is returned after every snippet");
+        let empty = vm.program();
+        let mut running = vm.with_program(&program);
+        let mut printed = Vec::new();
+        let result = running.run(input, &mut printed);
+        self.vm = Some(running.with_program(empty));
+
+        output.write_all(&printed)?;
+        if printed.last().is_some_and(|&byte| byte != b'\n') {
+            writeln!(output)?;
+        }
+        if let Err(err) = result {
+            write!(
+                output,
+                "The snippet is stopped with {}",
+                err.render(&program, false)
+            )?;
+        }
+        self.show_tape(output)
+    }
+
+    /// Show cells around the head in one line, the current cell in brackets.
+    fn show_tape(&self, output: &mut impl Write) -> io::Result<()> {
+        let vm = self.vm();
+        let head = vm.head();
+        let start = head.saturating_sub(TAPE_RADIUS);
+        let end = head.saturating_add(TAPE_RADIUS).min(vm.tape_size() - 1);
+        write!(output, "head {} cells {}..={}:", head, start, end)?;
+        for index in start..=end {
+            let value = vm.cell(index).unwrap_or_default();
+            if index == head {
+                write!(output, " [{}]", value)?;
+            } else {
+                write!(output, " {}", value)?;
+            }
+        }
+        writeln!(output)
+    }
+
+    /// Show the head, the step count, the size of the tape and all non-zero cells.
+    fn dump(&self, output: &mut impl Write) -> io::Result<()> {
+        let vm = self.vm();
+        writeln!(
+            output,
+            "head {}, {} steps, tape of {} cells",
+            vm.head(),
+            vm.steps(),
+            vm.tape_size()
+        )?;
+        for index in 0..vm.tape_size() {
+            match vm.cell(index) {
+                Some(value) if value != T::Value::default() => {
+                    writeln!(output, "  {:>8}: {}", index, value)?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check if the snippet has `[` without matching `]` and no `]` without matching `[`, so it
+/// continues on the next lines.
+fn has_unclosed_brackets(snippet: &BrainFuckProgram) -> bool {
+    let mut depth: usize = 0;
+    for instruction in snippet.instructions() {
+        match instruction.instruction() {
+            RawInstructions::ZeroJump => depth += 1,
+            RawInstructions::NonZeroJump => match depth.checked_sub(1) {
+                Some(outer) => depth = outer,
+                None => return false,
+            },
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repl::Repl;
+    use btf_interp::ir::{OptimizationLevel, Program};
+    use btf_interp::VirtualMachine;
+    use btf_types::{BrainFuckProgram, Extensions};
+    use std::env;
+    use std::fs;
+    use std::num::NonZeroUsize;
+    use std::process;
+
+    /// Compile the empty program VM holds between snippets.
+    fn empty() -> Program {
+        let empty = BrainFuckProgram::new("<repl>", String::new(), Extensions::default());
+        Program::compile(
+            &empty.validate_brackets().unwrap(),
+            OptimizationLevel::Offsets,
+        )
+    }
+
+    /// Execute lines in REPL with the tape of 10 cells, returning its replies.
+    fn repl(empty: &Program, lines: &str) -> String {
+        let vm: VirtualMachine<u8> = VirtualMachine::new(empty, NonZeroUsize::new(10), None);
+        let mut repl = Repl::new(vm, Extensions::default(), OptimizationLevel::Offsets);
+        let mut input: &[u8] = b"A";
+        let mut output = Vec::new();
+        repl.repl(&mut input, &mut lines.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_tape_persists_between_lines() {
+        let empty = empty();
+        assert_eq!(
+            repl(&empty, "+++\n>++\n[<+>-]<.\n"),
+            "bf> head 0 cells 0..=8: [3] 0 0 0 0 0 0 0 0\n\
+             bf> head 1 cells 0..=9: 3 [2] 0 0 0 0 0 0 0 0\n\
+             bf> \u{5}\n\
+             head 0 cells 0..=8: [5] 0 0 0 0 0 0 0 0\n\
+             bf> \n"
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_lines() {
+        let empty = empty();
+        assert_eq!(
+            repl(&empty, "++[\n>+\n<-]\n"),
+            "bf> ... ... head 0 cells 0..=8: [0] 2 0 0 0 0 0 0 0\nbf> \n"
+        );
+        assert_eq!(
+            repl(&empty, "+]\n+\n"),
+            "bf> error: no open bracket found matching bracket\n \
+             --> <repl>:1:2\n  \
+             |\n\
+             1 | +]\n  \
+             |  ^ unmatched bracket\n\
+             bf> head 0 cells 0..=8: [1] 0 0 0 0 0 0 0 0\n\
+             bf> \n"
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        let path = env::temp_dir().join(format!("btf-repl-{}.bf", process::id()));
+        fs::write(&path, ",>+++").unwrap();
+        let empty = empty();
+        let output = repl(
+            &empty,
+            &format!(
+                "+>\n:load {}\n:dump\n:reset\n:dump\n:quit\n+\n",
+                path.display()
+            ),
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            output,
+            concat!(
+                "bf> head 1 cells 0..=9: 1 [0] 0 0 0 0 0 0 0 0\n",
+                "bf> head 2 cells 0..=9: 1 65 [3] 0 0 0 0 0 0 0\n",
+                "bf> head 2, 7 steps, tape of 10 cells\n",
+                "         0: 1\n",
+                "         1: 65\n",
+                "         2: 3\n",
+                "bf> head 0 cells 0..=8: [0] 0 0 0 0 0 0 0 0\n",
+                "bf> head 0, 0 steps, tape of 10 cells\n",
+                "bf> ",
+            )
+        );
+    }
+}